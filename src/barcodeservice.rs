@@ -1,15 +1,27 @@
 use async_stream::stream;
 use futures::Stream;
 use libc::ioctl;
+use regex::Regex;
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::error::Error;
 use std::fs::File;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use tokio::io::AsyncReadExt;
-use tokio::time::{sleep, Duration};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::Duration;
 use tokio_fd::AsyncFd;
 
+use crate::hotplug::HotplugEvent;
+use crate::supervisor::{Interruption, Supervisor, Supervisors};
+
+const INPUT_DIR: &str = "/dev/input";
+// only used if there is no hotplug detection
+const RESCAN_INTERVAL: Duration = Duration::from_secs(5);
+
 // tja...wenn man es halt nicht kann?!
 fn u8_8(u: &[u8]) -> [u8; 8] {
     [u[0], u[1], u[2], u[3], u[4], u[5], u[6], u[7]]
@@ -26,6 +38,131 @@ fn u8_2(u: &[u8]) -> [u8; 2] {
 // from linux/input.h "grabs" the keyboard....i.e. keyboard input is exclusively readable by us
 const EVIOCGRAB: u64 = 1074021776;
 
+#[repr(C)]
+#[derive(Debug, Default)]
+struct InputId {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+}
+
+nix::ioctl_read!(eviocgid, b'E', 0x02, InputId);
+nix::ioctl_read_buf!(eviocgname, b'E', 0x06, u8);
+nix::ioctl_read_buf!(eviocgphys, b'E', 0x07, u8);
+
+fn ioctl_string(
+    fd: RawFd,
+    ioctl: unsafe fn(RawFd, &mut [u8]) -> nix::Result<libc::c_int>,
+) -> nix::Result<String> {
+    let mut buf = [0u8; 256];
+    let len = unsafe { ioctl(fd, &mut buf)? } as usize;
+    let s = String::from_utf8_lossy(&buf[..len.min(buf.len())]);
+    Ok(s.trim_end_matches('\0').to_string())
+}
+
+/// Identification of an evdev input device
+#[derive(Debug, Clone)]
+pub struct InputDeviceInfo {
+    pub name: String,
+    pub phys: String,
+    pub vendor: u16,
+    pub product: u16,
+    /// resolved `/sys/class/input/eventX/device`, contains the USB port the device is plugged into
+    pub sysfs_path: Option<PathBuf>,
+}
+
+impl InputDeviceInfo {
+    pub fn read(dev: &Path) -> Result<InputDeviceInfo, Box<dyn Error>> {
+        let file = File::open(dev)?;
+        let fd = file.as_raw_fd();
+        let mut id = InputId::default();
+        unsafe { eviocgid(fd, &mut id)? };
+        let name = ioctl_string(fd, eviocgname)?;
+        // virtual devices don't have a physical path
+        let phys = ioctl_string(fd, eviocgphys).unwrap_or_default();
+        let sysfs_path = dev
+            .canonicalize()
+            .ok()
+            .and_then(|dev| dev.file_name().map(|n| n.to_owned()))
+            .and_then(|node| {
+                Path::new("/sys/class/input")
+                    .join(node)
+                    .join("device")
+                    .canonicalize()
+                    .ok()
+            });
+        Ok(InputDeviceInfo {
+            name,
+            phys,
+            vendor: id.vendor,
+            product: id.product,
+            sysfs_path,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsbId {
+    pub vendor: u16,
+    pub product: u16,
+}
+
+impl FromStr for UsbId {
+    type Err = Box<dyn Error>;
+
+    /// Parses the `vendor:product` notation `lsusb` uses, e.g. `1eab:1d06`
+    fn from_str(s: &str) -> Result<UsbId, Self::Err> {
+        let (vendor, product) = s
+            .split_once(':')
+            .ok_or_else(|| format!("Invalid USB id {}, expected vendor:product", s))?;
+        Ok(UsbId {
+            vendor: u16::from_str_radix(vendor, 16)?,
+            product: u16::from_str_radix(product, 16)?,
+        })
+    }
+}
+
+/// Criteria a scanner has to fulfill. Unset criteria match everything.
+#[derive(Debug, Default, Clone)]
+pub struct DeviceMatcher {
+    pub usb_id: Option<UsbId>,
+    pub name: Option<Regex>,
+    /// substring of either the evdev physical path or the sysfs device path
+    pub phys: Option<String>,
+}
+
+impl DeviceMatcher {
+    pub fn is_empty(&self) -> bool {
+        self.usb_id.is_none() && self.name.is_none() && self.phys.is_none()
+    }
+
+    pub fn matches(&self, info: &InputDeviceInfo) -> bool {
+        let usb_id_matches = self
+            .usb_id
+            .is_none_or(|id| id.vendor == info.vendor && id.product == info.product);
+        let name_matches = self
+            .name
+            .as_ref()
+            .is_none_or(|name| name.is_match(&info.name));
+        let phys_matches = self.phys.as_ref().is_none_or(|phys| {
+            info.phys.contains(phys.as_str())
+                || info
+                    .sysfs_path
+                    .as_ref()
+                    .is_some_and(|path| path.to_string_lossy().contains(phys.as_str()))
+        });
+        usb_id_matches && name_matches && phys_matches
+    }
+}
+
+pub enum BarcodeSource {
+    /// A fixed device node
+    Device(PathBuf),
+    /// Every input device matching
+    Match(DeviceMatcher),
+}
+
 fn create_input_event(buf: &[u8]) -> libc::input_event {
     libc::input_event {
         time: libc::timeval {
@@ -63,67 +200,60 @@ impl KeyboardFile {
     }
 }
 
+impl Drop for KeyboardFile {
+    fn drop(&mut self) {
+        // hand the keyboard back to the system
+        unsafe {
+            ioctl(self._file.as_raw_fd(), EVIOCGRAB, 0);
+        }
+    }
+}
+
 struct BarcodeScanner {
     dev: PathBuf,
     keyboard_file: Option<KeyboardFile>,
-    first_sleep_secs: Option<u64>,
+    supervisor: Supervisor,
 }
 
 impl BarcodeScanner {
-    pub fn new(dev: impl Into<PathBuf>) -> BarcodeScanner {
+    pub fn new(dev: impl Into<PathBuf>, supervisor: Supervisor) -> BarcodeScanner {
         BarcodeScanner {
             dev: dev.into(),
             keyboard_file: None,
-            // see acquire_fd
-            first_sleep_secs: Some(0),
+            supervisor,
         }
     }
 
-    pub async fn acquire_keyboard_fd(&mut self) -> &mut AsyncFd {
-        if self.keyboard_file.is_none() {
-            // special case for the very first call (try to acquire immediately)
-            // if the fd somehow became None after the first call we had an error
-            // and should wait before trying again
-            let mut sleep_secs = self.first_sleep_secs.take().unwrap_or(1);
-            while self.keyboard_file.is_none() {
-                sleep(Duration::from_secs(sleep_secs)).await;
-                self.keyboard_file = match KeyboardFile::new(&self.dev) {
-                    Ok(fd) => Some(fd),
-                    Err(e) => {
-                        tracing::error!("Error accessing keyboard {}", e);
-                        if sleep_secs == 0 {
-                            sleep_secs = 1;
-                        } else {
-                            sleep_secs = sleep_secs * 2;
-                            if sleep_secs > 4 {
-                                sleep_secs = 4;
-                            }
-                        }
-                        None
-                    }
-                }
-            }
-        }
-
-        self.keyboard_file.as_mut().unwrap().fd_mut()
-    }
-
-    pub async fn try_read_barcode(&mut self) -> Result<String, Box<dyn Error>> {
+    pub async fn try_read_barcode(&mut self) -> Result<Option<String>, Box<dyn Error>> {
         let input_event_size = std::mem::size_of::<libc::input_event>();
         let mut buf = [0u8; 2048];
         let mut s = String::new();
 
-        let fd = self.acquire_keyboard_fd().await;
+        if self.keyboard_file.is_none() {
+            let dev = &self.dev;
+            self.keyboard_file = self.supervisor.acquire(|| KeyboardFile::new(dev)).await;
+        }
+        let fd = match self.keyboard_file.as_mut() {
+            Some(f) => f.fd_mut(),
+            // cancelled while waiting for the device
+            None => return Ok(None),
+        };
 
         loop {
-            let r = fd.read(&mut buf).await?;
+            let r = tokio::select! {
+                r = fd.read(&mut buf) => r?,
+                interruption = self.supervisor.interrupted() => match interruption {
+                    Interruption::Cancelled => return Ok(None),
+                    Interruption::Removed => return Err("device removed".into()),
+                },
+            };
             // not sure if this can even happen but chunks_exact panics if r == 0
             if r == 0 {
                 continue;
             }
             // chunks_exact so our buffer is always large enough to contain a full input_event
-            for event_buf in buf.chunks_exact(input_event_size) {
-                let event = create_input_event(&event_buf);
+            for event_buf in buf[..r].chunks_exact(input_event_size) {
+                let event = create_input_event(event_buf);
 
                 if event.type_ != 1 {
                     continue;
@@ -143,8 +273,8 @@ impl BarcodeScanner {
                     10 => s += "9",
                     11 => s += "0",
                     28 => {
-                        if s.len() > 0 {
-                            return Ok(s);
+                        if !s.is_empty() {
+                            return Ok(Some(s));
                         }
                         tracing::warn!("Tried submitting empty barcode. Skipping.");
                         // ignore everything so far...expect new, clean barcode
@@ -159,13 +289,14 @@ impl BarcodeScanner {
         }
     }
 
-    pub async fn read_barcode(&mut self) -> String {
+    /// Returns `None` once the supervisor has been cancelled or the device is gone for good
+    pub async fn read_barcode(&mut self) -> Option<String> {
         loop {
             match self.try_read_barcode().await {
                 Ok(s) => return s,
                 Err(e) => {
-                    // todo logging
-                    tracing::error!("Error reading barcode {}", e);
+                    self.supervisor
+                        .connection_lost(format!("Error reading barcode {}", e));
                     self.keyboard_file = None
                 }
             }
@@ -173,11 +304,177 @@ impl BarcodeScanner {
     }
 }
 
-pub fn run(dev: impl Into<PathBuf>) -> impl Stream<Item = String> {
+async fn scan(mut scanner: BarcodeScanner, tx: mpsc::Sender<String>) {
+    while let Some(barcode) = scanner.read_barcode().await {
+        if tx.send(barcode).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn next_input_change(events: &mut Option<broadcast::Receiver<HotplugEvent>>) {
+    let events = match events {
+        Some(events) => events,
+        None => return tokio::time::sleep(RESCAN_INTERVAL).await,
+    };
+    loop {
+        match events.recv().await {
+            Ok(HotplugEvent::Added(path)) if path.starts_with(INPUT_DIR) => return,
+            Ok(_) => continue,
+            Err(broadcast::error::RecvError::Lagged(_)) => return,
+            Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+        }
+    }
+}
+
+/// Grab every input device matching `matcher`, now and whenever a new one is plugged in
+async fn discover(matcher: DeviceMatcher, supervisors: Supervisors, tx: mpsc::Sender<String>) {
+    let active = Arc::new(Mutex::new(HashSet::new()));
+    let mut events = supervisors
+        .hotplug()
+        .map(|hotplug| hotplug.watch_dir(Path::new(INPUT_DIR)));
+    let cancel = supervisors.cancel_token().clone();
+
+    loop {
+        let nodes = std::fs::read_dir(INPUT_DIR)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.file_name()
+                    .is_some_and(|name| name.to_string_lossy().starts_with("event"))
+            });
+        for dev in nodes {
+            if active.lock().unwrap().contains(&dev) {
+                continue;
+            }
+            let info = match InputDeviceInfo::read(&dev) {
+                Ok(info) => info,
+                Err(e) => {
+                    tracing::debug!("Could not identify input device {:?} {}", dev, e);
+                    continue;
+                }
+            };
+            if !matcher.matches(&info) {
+                continue;
+            }
+            tracing::info!("Found barcode scanner {:?} at {:?}", info, dev);
+
+            let name = format!(
+                "barcode:{}",
+                dev.file_name().unwrap_or_default().to_string_lossy()
+            );
+            let supervisor = supervisors.device_supervisor(&name, &dev).transient(&dev);
+            active.lock().unwrap().insert(dev.clone());
+
+            let active = active.clone();
+            let board = supervisors.board().clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                scan(BarcodeScanner::new(dev.clone(), supervisor), tx).await;
+                tracing::info!("Barcode scanner at {:?} is gone", dev);
+                active.lock().unwrap().remove(&dev);
+                board.unregister(&name);
+            });
+        }
+
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            _ = next_input_change(&mut events) => {},
+        }
+    }
+}
+
+pub fn run(source: BarcodeSource, supervisors: Supervisors) -> impl Stream<Item = String> {
+    let (tx, mut rx) = mpsc::channel(16);
+    match source {
+        BarcodeSource::Device(dev) => {
+            let supervisor = supervisors.device_supervisor("barcode", &dev);
+            tokio::spawn(scan(BarcodeScanner::new(dev, supervisor), tx));
+        }
+        BarcodeSource::Match(matcher) => {
+            tokio::spawn(discover(matcher, supervisors, tx));
+        }
+    }
+
     stream! {
-        let mut scanner = BarcodeScanner::new(dev);
-        loop {
-            yield scanner.read_barcode().await;
+        while let Some(barcode) = rx.recv().await {
+            yield barcode;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scanner() -> InputDeviceInfo {
+        InputDeviceInfo {
+            name: "Honeywell Scanner".to_string(),
+            phys: "usb-0000:00:14.0-2/input0".to_string(),
+            vendor: 0x0c2e,
+            product: 0x0b61,
+            sysfs_path: Some(PathBuf::from(
+                "/sys/devices/pci0000:00/0000:00:14.0/usb1/1-3/1-3:1.0",
+            )),
         }
     }
+
+    #[test]
+    fn parses_usb_id() {
+        assert_eq!(
+            "0c2e:0B61".parse::<UsbId>().unwrap(),
+            UsbId {
+                vendor: 0x0c2e,
+                product: 0x0b61,
+            }
+        );
+        assert!("0c2e".parse::<UsbId>().is_err());
+        assert!("0c2e:".parse::<UsbId>().is_err());
+        assert!("0c2e:0b61:1".parse::<UsbId>().is_err());
+        assert!("10000:0b61".parse::<UsbId>().is_err());
+        assert!("scanner:0b61".parse::<UsbId>().is_err());
+    }
+
+    #[test]
+    fn matches_all_criteria() {
+        let info = scanner();
+        assert!(DeviceMatcher::default().matches(&info));
+
+        let by_id = |id: &str| DeviceMatcher {
+            usb_id: Some(id.parse().unwrap()),
+            ..Default::default()
+        };
+        assert!(by_id("0c2e:0b61").matches(&info));
+        assert!(!by_id("0c2e:0b62").matches(&info));
+
+        let by_name = |name: &str| DeviceMatcher {
+            name: Some(Regex::new(name).unwrap()),
+            ..Default::default()
+        };
+        assert!(by_name("^Honeywell").matches(&info));
+        assert!(!by_name("^Scanner").matches(&info));
+
+        // either the evdev physical path or the sysfs path
+        let by_phys = |phys: &str| DeviceMatcher {
+            phys: Some(phys.to_string()),
+            ..Default::default()
+        };
+        assert!(by_phys("usb-0000:00:14.0-2").matches(&info));
+        assert!(by_phys("usb1/1-3/").matches(&info));
+        assert!(!by_phys("usb1/1-4/").matches(&info));
+        let no_sysfs = InputDeviceInfo {
+            sysfs_path: None,
+            ..scanner()
+        };
+        assert!(!by_phys("usb1/1-3/").matches(&no_sysfs));
+
+        let all = DeviceMatcher {
+            usb_id: Some("0c2e:0b61".parse().unwrap()),
+            name: Some(Regex::new("Scanner").unwrap()),
+            phys: Some("usb1/1-4/".to_string()),
+        };
+        assert!(!all.matches(&info));
+    }
 }
@@ -0,0 +1,108 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use std::sync::OnceLock;
+
+use crate::hex;
+
+// set once at startup if identifiers have to be kept out of the logs
+static LOG_REDACTION: OnceLock<CardIdHasher> = OnceLock::new();
+
+/// Keyed HMAC-SHA256 of card identifiers.
+///
+/// The same card always maps to the same value, so a card registered in Mete by its
+/// hashed id keeps working, but without the secret nobody can tell which card it was.
+#[derive(Clone)]
+pub struct CardIdHasher {
+    mac: Hmac<Sha256>,
+}
+
+impl CardIdHasher {
+    pub fn new(secret: &[u8]) -> CardIdHasher {
+        CardIdHasher {
+            mac: Hmac::new_from_slice(secret).expect("HMAC accepts keys of any length"),
+        }
+    }
+
+    pub fn hash(&self, id: &[u8]) -> Vec<u8> {
+        let mut mac = self.mac.clone();
+        mac.update(id);
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// Short stand-in for `id`, good enough to correlate log lines
+    pub fn redact(&self, id: &str) -> String {
+        format!("redacted:{}", &hex::encode(&self.hash(id.as_bytes()))[..12])
+    }
+
+    /// Keep card identifiers out of the tracing output from now on
+    pub fn redact_logs(self) {
+        if LOG_REDACTION.set(self).is_err() {
+            tracing::warn!("Log redaction is already configured");
+        }
+    }
+}
+
+pub fn logs_redacted() -> bool {
+    LOG_REDACTION.get().is_some()
+}
+
+/// Formats a card identifier for the logs, redacted if configured
+pub struct Redacted<'a>(pub &'a str);
+
+impl fmt::Display for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match LOG_REDACTION.get() {
+            Some(hasher) => f.write_str(&hasher.redact(self.0)),
+            None => f.write_str(self.0),
+        }
+    }
+}
+
+impl fmt::Debug for Redacted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match LOG_REDACTION.get() {
+            Some(hasher) => f.write_str(&hasher.redact(self.0)),
+            None => write!(f, "{:?}", self.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_depends_on_id_and_key() {
+        let hasher = CardIdHasher::new(b"secret");
+        assert_eq!(hasher.hash(b"04aabbcc"), hasher.hash(b"04aabbcc"));
+        assert_eq!(hasher.hash(b"04aabbcc").len(), 32);
+        assert_ne!(hasher.hash(b"04aabbcc"), hasher.hash(b"04aabbcd"));
+        assert_ne!(
+            hasher.hash(b"04aabbcc"),
+            CardIdHasher::new(b"other").hash(b"04aabbcc")
+        );
+
+        let redacted = hasher.redact("04aabbcc");
+        assert_eq!(redacted, hasher.redact("04aabbcc"));
+        assert_eq!(
+            redacted,
+            format!("redacted:{}", &hex::encode(&hasher.hash(b"04aabbcc"))[..12])
+        );
+    }
+
+    // the only test setting the global redaction
+    #[test]
+    fn redacted_formatting() {
+        let id = Redacted("04aabbcc");
+        assert!(!logs_redacted());
+        assert_eq!(format!("{}", id), "04aabbcc");
+        assert_eq!(format!("{:?}", id), "\"04aabbcc\"");
+
+        let hasher = CardIdHasher::new(b"secret");
+        hasher.clone().redact_logs();
+        assert!(logs_redacted());
+        assert_eq!(format!("{}", id), hasher.redact("04aabbcc"));
+        assert_eq!(format!("{:?}", id), hasher.redact("04aabbcc"));
+    }
+}
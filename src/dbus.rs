@@ -0,0 +1,230 @@
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use zbus::{interface, Connection, SignalContext};
+
+use crate::eventbus::{EventBus, Observer};
+use crate::supervisor::{DeviceStatus, StatusBoard};
+
+pub const BUS_NAME: &str = "de.kalkspace.Getraenkekasse";
+pub const OBJECT_PATH: &str = "/de/kalkspace/Getraenkekasse";
+pub const INTERFACE: &str = "de.kalkspace.Getraenkekasse1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bus {
+    Session,
+    System,
+}
+
+impl FromStr for Bus {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "session" => Ok(Bus::Session),
+            "system" => Ok(Bus::System),
+            _ => Err(format!("invalid bus {:?}, expected session or system", s)),
+        }
+    }
+}
+
+struct Kasse {
+    devices: StatusBoard,
+}
+
+#[interface(name = "de.kalkspace.Getraenkekasse1")]
+impl Kasse {
+    /// Device name -> status as JSON, the same as `GET /devices`
+    #[zbus(property)]
+    async fn devices(&self) -> HashMap<String, String> {
+        self.devices
+            .snapshot()
+            .into_iter()
+            .map(|(name, status)| (name, serde_json::to_string(&status).unwrap()))
+            .collect()
+    }
+
+    /// Every event, `data` is JSON
+    #[zbus(signal)]
+    async fn event(
+        ctxt: &SignalContext<'_>,
+        event_type: &str,
+        device: &str,
+        data: &str,
+    ) -> zbus::Result<()>;
+
+    // the built-in event types are also sent as a signal of their own, named after
+    // the type, e.g. `NfcUuid(device, data)` for `nfc-uuid`. events of serial devices
+    // are only sent as `Event`, their names could clash with the declared members.
+    #[zbus(signal)]
+    async fn barcode(ctxt: &SignalContext<'_>, device: &str, data: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn barcode_drink(ctxt: &SignalContext<'_>, device: &str, data: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn nfc_uuid(ctxt: &SignalContext<'_>, device: &str, data: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn nfc_plain(ctxt: &SignalContext<'_>, device: &str, data: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn nfc_invalid(ctxt: &SignalContext<'_>, device: &str, data: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn nfc_removed(ctxt: &SignalContext<'_>, device: &str, data: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn nfc_user(ctxt: &SignalContext<'_>, device: &str, data: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn storno(ctxt: &SignalContext<'_>, device: &str, data: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn storno_pressed(ctxt: &SignalContext<'_>, device: &str, data: &str)
+        -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn storno_released(
+        ctxt: &SignalContext<'_>,
+        device: &str,
+        data: &str,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn storno_held(ctxt: &SignalContext<'_>, device: &str, data: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn session_updated(
+        ctxt: &SignalContext<'_>,
+        device: &str,
+        data: &str,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn session_timeout(
+        ctxt: &SignalContext<'_>,
+        device: &str,
+        data: &str,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn device_busy(ctxt: &SignalContext<'_>, device: &str, data: &str) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn server_shutdown(
+        ctxt: &SignalContext<'_>,
+        device: &str,
+        data: &str,
+    ) -> zbus::Result<()>;
+}
+
+/// Claims `BUS_NAME` and exports all events as signals and the device status as
+/// property until `cancel` fires
+pub async fn start(
+    bus: Bus,
+    events: &EventBus,
+    devices: StatusBoard,
+    cancel: CancellationToken,
+) -> zbus::Result<JoinHandle<()>> {
+    let builder = match bus {
+        Bus::Session => zbus::connection::Builder::session()?,
+        Bus::System => zbus::connection::Builder::system()?,
+    };
+    let connection = builder
+        .name(BUS_NAME)?
+        .serve_at(
+            OBJECT_PATH,
+            Kasse {
+                devices: devices.clone(),
+            },
+        )?
+        .build()
+        .await?;
+    tracing::info!("dbus: serving {} on the {:?} bus", BUS_NAME, bus);
+    Ok(tokio::spawn(serve(
+        connection,
+        events.observe(),
+        devices,
+        cancel,
+    )))
+}
+
+async fn emit(connection: &Connection, device: &str, r#type: &str, data: &str) -> zbus::Result<()> {
+    let ctxt = SignalContext::new(connection, OBJECT_PATH)?;
+    Kasse::event(&ctxt, r#type, device, data).await?;
+    match r#type {
+        "barcode" => Kasse::barcode(&ctxt, device, data).await,
+        "barcode-drink" => Kasse::barcode_drink(&ctxt, device, data).await,
+        "nfc-uuid" => Kasse::nfc_uuid(&ctxt, device, data).await,
+        "nfc-plain" => Kasse::nfc_plain(&ctxt, device, data).await,
+        "nfc-invalid" => Kasse::nfc_invalid(&ctxt, device, data).await,
+        "nfc-removed" => Kasse::nfc_removed(&ctxt, device, data).await,
+        "nfc-user" => Kasse::nfc_user(&ctxt, device, data).await,
+        "storno" => Kasse::storno(&ctxt, device, data).await,
+        "storno-pressed" => Kasse::storno_pressed(&ctxt, device, data).await,
+        "storno-released" => Kasse::storno_released(&ctxt, device, data).await,
+        "storno-held" => Kasse::storno_held(&ctxt, device, data).await,
+        "session-updated" => Kasse::session_updated(&ctxt, device, data).await,
+        "session-timeout" => Kasse::session_timeout(&ctxt, device, data).await,
+        "device-busy" => Kasse::device_busy(&ctxt, device, data).await,
+        "server-shutdown" => Kasse::server_shutdown(&ctxt, device, data).await,
+        _ => Ok(()),
+    }
+}
+
+async fn serve(
+    connection: Connection,
+    mut observer: Observer,
+    devices: StatusBoard,
+    cancel: CancellationToken,
+) {
+    let iface = match connection
+        .object_server()
+        .interface::<_, Kasse>(OBJECT_PATH)
+        .await
+    {
+        Ok(iface) => iface,
+        Err(e) => {
+            tracing::error!("dbus: interface is gone {}", e);
+            return;
+        }
+    };
+    let mut watch = devices.watch();
+    let mut status: BTreeMap<String, DeviceStatus> = devices.snapshot();
+    loop {
+        tokio::select! {
+            // drain the events before noticing the shutdown, so server-shutdown goes out
+            biased;
+            message = observer.recv() => {
+                let (device, message) = match message {
+                    Some(message) => message,
+                    None => break,
+                };
+                let data = serde_json::to_string(&message.data).unwrap();
+                // events of the server itself have no device
+                let device = device.unwrap_or_default();
+                if let Err(e) = emit(&connection, &device, &message.r#type, &data).await {
+                    tracing::warn!("dbus: error emitting {} {}", message.r#type, e);
+                }
+            },
+            _ = watch.changed() => {
+                let current = devices.snapshot();
+                if current == status {
+                    continue;
+                }
+                status = current;
+                let changed = iface
+                    .get()
+                    .await
+                    .devices_changed(iface.signal_context())
+                    .await;
+                if let Err(e) = changed {
+                    tracing::warn!("dbus: error announcing device status {}", e);
+                }
+            },
+            _ = cancel.cancelled() => break,
+        }
+    }
+}
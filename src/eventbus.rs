@@ -0,0 +1,674 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::broadcast::{self, error::RecvError, error::TryRecvError};
+use tokio_util::sync::CancellationToken;
+
+use crate::unixtime;
+
+/// A single event as sent to the clients
+#[derive(Debug, Clone, Serialize)]
+pub struct Message {
+    pub r#type: String,
+    pub data: serde_json::Value,
+}
+
+// a message together with the device it came from
+#[derive(Debug, Clone)]
+struct Envelope {
+    // position in the order of publishing
+    seq: u64,
+    device: Option<String>,
+    message: Message,
+    // with exclusive consumers the only subscriber to get the message, picked when
+    // sending so subscribers connecting later can't take it away
+    consumer: Option<u64>,
+    // lease on the device when sending, later changes only affect later messages
+    lease: Option<(u64, Instant)>,
+    // the data identifies a person, see `EventBus::publish_sensitive`
+    sensitive: bool,
+}
+
+/// What to do with a subscriber that doesn't keep up with the events
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// skip the events it missed and carry on
+    DropOldest,
+    /// give up on the subscriber
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<OverflowPolicy, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!("Invalid overflow policy {}", s)),
+        }
+    }
+}
+
+/// Who is listening
+#[derive(Debug, Clone)]
+pub struct SubscriberInfo {
+    pub remote_addr: Option<SocketAddr>,
+    pub user_agent: Option<String>,
+    /// only deliver these event types, everything if `None`
+    pub types: Option<BTreeSet<String>>,
+    pub connected_at: SystemTime,
+}
+
+impl Default for SubscriberInfo {
+    fn default() -> SubscriberInfo {
+        SubscriberInfo {
+            remote_addr: None,
+            user_agent: None,
+            types: None,
+            connected_at: SystemTime::now(),
+        }
+    }
+}
+
+impl SubscriberInfo {
+    pub fn wants(&self, message: &Message) -> bool {
+        self.types
+            .as_ref()
+            .is_none_or(|types| types.contains(&message.r#type))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SubscriberStatus {
+    pub remote_addr: Option<SocketAddr>,
+    pub user_agent: Option<String>,
+    pub types: Option<BTreeSet<String>>,
+    /// unix timestamp in milliseconds
+    pub connected_at_ms: u64,
+    /// events for the subscriber not yet picked up, not counting the ones it filters out
+    /// or that go to another subscriber
+    pub queued: u64,
+    /// events for the subscriber it missed because it was too slow
+    pub dropped: u64,
+    /// events handed to the subscriber
+    pub delivered: u64,
+}
+
+struct Entry {
+    info: SubscriberInfo,
+    // `seq` of the events in the ring the subscriber will get, oldest first
+    pending: Mutex<VecDeque<u64>>,
+    dropped: AtomicU64,
+    delivered: AtomicU64,
+    // fires if the subscriber was kicked out
+    disconnect: CancellationToken,
+}
+
+struct Lease {
+    holder: u64,
+    expires: Instant,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LeaseStatus {
+    pub holder: u64,
+    pub expires_in_ms: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LeaseError {
+    UnknownSubscriber,
+    Busy { device: String, holder: u64 },
+}
+
+impl fmt::Display for LeaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LeaseError::UnknownSubscriber => write!(f, "unknown client"),
+            LeaseError::Busy { device, holder } => {
+                write!(f, "{} is leased by client {}", device, holder)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LeaseError {}
+
+/// Upper bound for a single lease, clients have to renew it after that
+pub const MAX_LEASE: Duration = Duration::from_secs(600);
+
+struct Shared {
+    sender: broadcast::Sender<Envelope>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    published: AtomicU64,
+    exclusive: AtomicBool,
+    next_id: AtomicU64,
+    subscribers: Mutex<BTreeMap<u64, Arc<Entry>>>,
+    // device name -> exclusive lease
+    leases: Mutex<HashMap<String, Lease>>,
+    closed: CancellationToken,
+}
+
+impl Shared {
+    fn lease_holder(&self, device: &str) -> Option<(u64, Instant)> {
+        let leases = self.leases.lock().unwrap();
+        leases
+            .get(device)
+            .filter(|lease| lease.expires > Instant::now())
+            .map(|lease| (lease.holder, lease.expires))
+    }
+
+    // with exclusive consumers only the newest interested subscriber gets the message
+    fn consumer(&self, subscribers: &BTreeMap<u64, Arc<Entry>>, message: &Message) -> Option<u64> {
+        if !self.exclusive.load(Ordering::Relaxed) {
+            return None;
+        }
+        subscribers
+            .iter()
+            .rev()
+            .find(|(_, entry)| entry.info.wants(message))
+            .map(|(id, _)| *id)
+    }
+}
+
+/// Fan out of device events to any number of subscribers.
+///
+/// There are no queues per subscriber, all of them read from one ring buffer of the
+/// last `capacity` events. A subscriber falling behind by more than that misses
+/// events, what happens then is up to the `OverflowPolicy`. The ring holds every
+/// event, so events a subscriber filters out still take up room.
+#[derive(Clone)]
+pub struct EventBus {
+    shared: Arc<Shared>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> EventBus {
+        let capacity = capacity.max(1);
+        let (sender, _) = broadcast::channel(capacity);
+        EventBus {
+            shared: Arc::new(Shared {
+                sender,
+                capacity,
+                policy,
+                published: AtomicU64::new(0),
+                exclusive: AtomicBool::new(false),
+                next_id: AtomicU64::new(1),
+                subscribers: Mutex::new(BTreeMap::new()),
+                leases: Mutex::new(HashMap::new()),
+                closed: CancellationToken::new(),
+            }),
+        }
+    }
+
+    /// Deliver every device event type only to the most recent subscriber interested in
+    /// it, i.e. a forgotten browser tab stops acting on scans once a new one connected.
+    /// Events of the server itself still go to everyone.
+    pub fn with_exclusive_consumers(self, exclusive: bool) -> EventBus {
+        self.shared.exclusive.store(exclusive, Ordering::Relaxed);
+        self
+    }
+
+    /// Send `message` to all subscribers. Returns the number of subscribers
+    pub fn publish(&self, message: Message) -> usize {
+        self.send(None, None, false, message)
+    }
+
+    /// Same as `publish` for events of `device`, which might be leased by a subscriber.
+    /// Returns the lease holder, if any, which is the only one getting the message.
+    pub fn publish_from(&self, device: &str, message: Message) -> Option<u64> {
+        let lease = self.shared.lease_holder(device);
+        self.send(Some(device.to_string()), lease, false, message);
+        lease.map(|(holder, _)| holder)
+    }
+
+    /// Same as `publish_from` for data identifying a person, e.g. a card id. Observers
+    /// keeping records of the events are told to redact it.
+    pub fn publish_sensitive(&self, device: &str, message: Message) -> Option<u64> {
+        let lease = self.shared.lease_holder(device);
+        self.send(Some(device.to_string()), lease, true, message);
+        lease.map(|(holder, _)| holder)
+    }
+
+    fn send(
+        &self,
+        device: Option<String>,
+        lease: Option<(u64, Instant)>,
+        sensitive: bool,
+        message: Message,
+    ) -> usize {
+        // locked until the message is in the ring, so the pending events are in order
+        let subscribers = self.shared.subscribers.lock().unwrap();
+        // server events like server-shutdown or session-updated concern every frontend
+        let consumer = match &device {
+            Some(_) => self.shared.consumer(&subscribers, &message),
+            None => None,
+        };
+        let seq = self.shared.published.fetch_add(1, Ordering::SeqCst);
+        for (id, entry) in subscribers.iter() {
+            // lease holders get the message, everyone else a device-busy notice
+            let gets = lease.is_some() || consumer.is_none_or(|consumer| consumer == *id);
+            entry.sent(
+                seq,
+                self.shared.capacity,
+                gets && entry.info.wants(&message),
+            );
+        }
+        let envelope = Envelope {
+            seq,
+            device,
+            consumer,
+            lease,
+            sensitive,
+            message,
+        };
+        // nobody listening is fine
+        self.shared.sender.send(envelope).unwrap_or(0)
+    }
+
+    /// Give subscriber `id` exclusive access to the events of `devices` for `duration`
+    /// (at most `MAX_LEASE`). Renews leases the subscriber already holds. Either all
+    /// devices are leased or none.
+    pub fn lease(
+        &self,
+        id: u64,
+        devices: &[String],
+        duration: Duration,
+    ) -> Result<Instant, LeaseError> {
+        if !self.shared.subscribers.lock().unwrap().contains_key(&id) {
+            return Err(LeaseError::UnknownSubscriber);
+        }
+        let now = Instant::now();
+        let mut leases = self.shared.leases.lock().unwrap();
+        for device in devices {
+            match leases.get(device) {
+                Some(lease) if lease.holder != id && lease.expires > now => {
+                    return Err(LeaseError::Busy {
+                        device: device.clone(),
+                        holder: lease.holder,
+                    })
+                }
+                _ => {}
+            }
+        }
+        let expires = now + duration.min(MAX_LEASE);
+        for device in devices {
+            tracing::info!("Client {} leased {}", id, device);
+            leases.insert(
+                device.clone(),
+                Lease {
+                    holder: id,
+                    expires,
+                },
+            );
+        }
+        Ok(expires)
+    }
+
+    /// Give up the leases of subscriber `id` on `devices`, or all of its leases
+    pub fn release(&self, id: u64, devices: Option<&[String]>) {
+        let mut leases = self.shared.leases.lock().unwrap();
+        leases.retain(|device, lease| {
+            lease.holder != id || devices.is_some_and(|devices| !devices.contains(device))
+        });
+    }
+
+    /// All active leases keyed by device
+    pub fn leases(&self) -> BTreeMap<String, LeaseStatus> {
+        let now = Instant::now();
+        let leases = self.shared.leases.lock().unwrap();
+        leases
+            .iter()
+            .filter(|(_, lease)| lease.expires > now)
+            .map(|(device, lease)| {
+                let status = LeaseStatus {
+                    holder: lease.holder,
+                    expires_in_ms: (lease.expires - now).as_millis() as u64,
+                };
+                (device.clone(), status)
+            })
+            .collect()
+    }
+
+    pub fn subscribe(&self, info: SubscriberInfo) -> Subscriber {
+        let id = self.shared.next_id.fetch_add(1, Ordering::Relaxed);
+        // subscribe first so we can't miss a message counted in `start`
+        let receiver = self.shared.sender.subscribe();
+        let entry = Arc::new(Entry {
+            info,
+            pending: Mutex::new(VecDeque::new()),
+            dropped: AtomicU64::new(0),
+            delivered: AtomicU64::new(0),
+            disconnect: CancellationToken::new(),
+        });
+        let mut subscribers = self.shared.subscribers.lock().unwrap();
+        subscribers.insert(id, entry.clone());
+        tracing::debug!("Subscribers: {}", subscribers.len());
+        Subscriber {
+            id,
+            receiver,
+            entry,
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Every published event with the device it came from, for bridges to other
+    /// systems. Observers are not affected by filters, leases or exclusive consumers
+    /// and don't show up as subscribers.
+    pub fn observe(&self) -> Observer {
+        Observer {
+            receiver: self.shared.sender.subscribe(),
+            closed: self.shared.closed.clone(),
+        }
+    }
+
+    pub fn subscribers(&self) -> BTreeMap<u64, SubscriberStatus> {
+        let subscribers = self.shared.subscribers.lock().unwrap();
+        subscribers
+            .iter()
+            .map(|(id, entry)| (*id, entry.status()))
+            .collect()
+    }
+
+    /// Close the stream of subscriber `id`. Returns `false` if there is no such subscriber
+    pub fn disconnect(&self, id: u64) -> bool {
+        match self.shared.subscribers.lock().unwrap().get(&id) {
+            Some(entry) => {
+                tracing::info!("Disconnecting subscriber {}", id);
+                entry.disconnect.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Let all subscribers finish the events already published and stop
+    pub fn close(&self) {
+        self.shared.closed.cancel();
+    }
+}
+
+impl Entry {
+    // event `seq` went into the ring, `wanted` if it is for the subscriber
+    fn sent(&self, seq: u64, capacity: usize, wanted: bool) {
+        let mut pending = self.pending.lock().unwrap();
+        // pushed out of the ring by now
+        while pending
+            .front()
+            .is_some_and(|front| front + capacity as u64 <= seq)
+        {
+            pending.pop_front();
+            self.dropped.fetch_add(1, Ordering::SeqCst);
+        }
+        if wanted {
+            pending.push_back(seq);
+        }
+    }
+
+    // event `seq` was taken out of the ring, anything pending before it is lost
+    fn take(&self, seq: u64) {
+        let mut pending = self.pending.lock().unwrap();
+        while let Some(&front) = pending.front() {
+            if front > seq {
+                break;
+            }
+            pending.pop_front();
+            if front < seq {
+                self.dropped.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    fn status(&self) -> SubscriberStatus {
+        let connected_at_ms = unixtime::millis(self.info.connected_at);
+        SubscriberStatus {
+            remote_addr: self.info.remote_addr,
+            user_agent: self.info.user_agent.clone(),
+            types: self.info.types.clone(),
+            connected_at_ms,
+            queued: self.pending.lock().unwrap().len() as u64,
+            dropped: self.dropped.load(Ordering::SeqCst),
+            delivered: self.delivered.load(Ordering::SeqCst),
+        }
+    }
+}
+
+pub struct Observer {
+    receiver: broadcast::Receiver<Envelope>,
+    closed: CancellationToken,
+}
+
+impl Observer {
+    /// Next event and its device. Returns `None` once the bus was closed.
+    pub async fn recv(&mut self) -> Option<(Option<String>, Message)> {
+        let (device, message, _) = self.recv_sensitive().await?;
+        Some((device, message))
+    }
+
+    /// Same as `recv`, also telling whether the event was published as sensitive
+    pub async fn recv_sensitive(&mut self) -> Option<(Option<String>, Message, bool)> {
+        loop {
+            let result = tokio::select! {
+                biased;
+                result = self.receiver.recv() => result,
+                _ = self.closed.cancelled() => match self.receiver.try_recv() {
+                    Ok(envelope) => Ok(envelope),
+                    Err(TryRecvError::Lagged(n)) => Err(RecvError::Lagged(n)),
+                    Err(_) => return None,
+                },
+            };
+            match result {
+                Ok(Envelope {
+                    device,
+                    message,
+                    sensitive,
+                    ..
+                }) => return Some((device, message, sensitive)),
+                Err(RecvError::Lagged(n)) => {
+                    tracing::warn!("Observer is not keeping up, dropped {} events", n);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+pub struct Subscriber {
+    id: u64,
+    receiver: broadcast::Receiver<Envelope>,
+    entry: Arc<Entry>,
+    shared: Arc<Shared>,
+}
+
+impl Subscriber {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Next message matching the subscriber's filter. Returns `None` once the bus
+    /// was closed or the subscriber fell behind with the `Disconnect` policy.
+    pub async fn recv(&mut self) -> Option<Message> {
+        loop {
+            let result = tokio::select! {
+                biased;
+                _ = self.entry.disconnect.cancelled() => return None,
+                result = self.receiver.recv() => result,
+                // deliver what's left and stop
+                _ = self.shared.closed.cancelled() => match self.receiver.try_recv() {
+                    Ok(envelope) => Ok(envelope),
+                    Err(TryRecvError::Lagged(n)) => Err(RecvError::Lagged(n)),
+                    Err(_) => return None,
+                },
+            };
+            match result {
+                Ok(Envelope {
+                    seq,
+                    device,
+                    message,
+                    consumer,
+                    lease,
+                    ..
+                }) => {
+                    self.entry.take(seq);
+                    if !self.entry.info.wants(&message) {
+                        continue;
+                    }
+                    let message = match lease {
+                        // the lease beats exclusive consumers
+                        Some((holder, _)) if holder == self.id => message,
+                        Some((holder, expires)) => Message {
+                            r#type: "device-busy".to_string(),
+                            data: serde_json::json!({
+                                "device": device,
+                                "event": message.r#type,
+                                "holder": holder,
+                                "expires_in_ms": expires
+                                    .saturating_duration_since(Instant::now())
+                                    .as_millis() as u64,
+                            }),
+                        },
+                        None if consumer.is_some_and(|consumer| consumer != self.id) => continue,
+                        None => message,
+                    };
+                    self.entry.delivered.fetch_add(1, Ordering::SeqCst);
+                    return Some(message);
+                }
+                // counted as dropped once the next event comes in
+                Err(RecvError::Lagged(n)) => match self.shared.policy {
+                    OverflowPolicy::DropOldest => {
+                        tracing::warn!(
+                            "Subscriber {} is not keeping up, dropped {} events",
+                            self.id,
+                            n
+                        );
+                    }
+                    OverflowPolicy::Disconnect => {
+                        tracing::warn!("Subscriber {} is not keeping up, disconnecting", self.id);
+                        return None;
+                    }
+                },
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.shared
+            .leases
+            .lock()
+            .unwrap()
+            .retain(|_, lease| lease.holder != self.id);
+        let mut subscribers = self.shared.subscribers.lock().unwrap();
+        subscribers.remove(&self.id);
+        tracing::debug!("Subscribers: {}", subscribers.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(r#type: &str) -> Message {
+        Message {
+            r#type: r#type.to_string(),
+            data: "".into(),
+        }
+    }
+
+    async fn next(subscriber: &mut Subscriber) -> Option<String> {
+        let message = tokio::time::timeout(Duration::from_millis(50), subscriber.recv()).await;
+        message.ok().flatten().map(|message| message.r#type)
+    }
+
+    #[tokio::test]
+    async fn exclusive_consumer_is_picked_when_sending() {
+        let events = EventBus::new(16, OverflowPolicy::DropOldest).with_exclusive_consumers(true);
+        let mut old = events.subscribe(SubscriberInfo::default());
+        let mut new = events.subscribe(SubscriberInfo::default());
+        events.publish_from("barcode", message("barcode"));
+        // connecting after the event was sent doesn't take it away from `new`
+        let mut newest = events.subscribe(SubscriberInfo::default());
+        events.close();
+
+        assert_eq!(next(&mut new).await.as_deref(), Some("barcode"));
+        assert_eq!(next(&mut old).await, None);
+        assert_eq!(next(&mut newest).await, None);
+    }
+
+    #[tokio::test]
+    async fn server_events_are_not_exclusive() {
+        let events = EventBus::new(16, OverflowPolicy::DropOldest).with_exclusive_consumers(true);
+        let mut old = events.subscribe(SubscriberInfo::default());
+        let mut new = events.subscribe(SubscriberInfo::default());
+        events.publish_from("barcode", message("barcode"));
+        events.publish(message("session-updated"));
+        events.publish(message("server-shutdown"));
+        events.close();
+
+        assert_eq!(next(&mut new).await.as_deref(), Some("barcode"));
+        for subscriber in [&mut old, &mut new] {
+            assert_eq!(next(subscriber).await.as_deref(), Some("session-updated"));
+            assert_eq!(next(subscriber).await.as_deref(), Some("server-shutdown"));
+        }
+    }
+
+    #[tokio::test]
+    async fn lease_is_stamped_when_sending() {
+        let events = EventBus::new(16, OverflowPolicy::DropOldest);
+        let mut holder = events.subscribe(SubscriberInfo::default());
+        let mut other = events.subscribe(SubscriberInfo::default());
+        let devices = ["barcode".to_string()];
+        events
+            .lease(holder.id(), &devices, Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(
+            events.publish_from("barcode", message("barcode")),
+            Some(holder.id())
+        );
+        // releasing doesn't hand out what was sent while leased
+        events.release(holder.id(), None);
+        events.publish_from("barcode", message("barcode"));
+        events.close();
+
+        assert_eq!(next(&mut holder).await.as_deref(), Some("barcode"));
+        assert_eq!(next(&mut holder).await.as_deref(), Some("barcode"));
+        assert_eq!(next(&mut other).await.as_deref(), Some("device-busy"));
+        assert_eq!(next(&mut other).await.as_deref(), Some("barcode"));
+    }
+
+    #[tokio::test]
+    async fn counts_only_events_for_the_subscriber() {
+        let events = EventBus::new(2, OverflowPolicy::DropOldest);
+        let mut barcodes = events.subscribe(SubscriberInfo {
+            types: Some(BTreeSet::from(["barcode".to_string()])),
+            ..Default::default()
+        });
+        let everything = events.subscribe(SubscriberInfo::default());
+        events.publish_from("nfc", message("nfc"));
+        events.publish_from("barcode", message("barcode"));
+        let status = events.subscribers();
+        assert_eq!(status[&barcodes.id()].queued, 1);
+        assert_eq!(status[&everything.id()].queued, 2);
+
+        // two more events push the barcode out of the shared ring
+        events.publish(message("session-updated"));
+        events.publish(message("server-shutdown"));
+        let status = events.subscribers()[&barcodes.id()].clone();
+        assert_eq!((status.queued, status.dropped, status.delivered), (0, 1, 0));
+        events.publish_from("barcode", message("barcode"));
+        events.close();
+
+        assert_eq!(next(&mut barcodes).await.as_deref(), Some("barcode"));
+        let status = events.subscribers()[&barcodes.id()].clone();
+        assert_eq!((status.queued, status.dropped, status.delivered), (0, 1, 1));
+    }
+}
@@ -0,0 +1,393 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::CancellationToken;
+
+use crate::cardid::CardIdHasher;
+use crate::eventbus::{Message, Observer};
+use crate::unixtime;
+
+const DAY_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Number of events `query` returns unless the filter asks for more or less
+pub const DEFAULT_LIMIT: usize = 1000;
+
+/// A published event as stored in the log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoggedEvent {
+    pub at_ms: u64,
+    pub device: Option<String>,
+    pub r#type: String,
+    pub data: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EventFilter {
+    /// unix timestamp in milliseconds
+    pub since: Option<u64>,
+    /// unix timestamp in milliseconds, exclusive
+    pub until: Option<u64>,
+    pub r#type: Option<String>,
+    pub device: Option<String>,
+    /// at most this many events, the oldest ones
+    pub limit: Option<usize>,
+}
+
+impl EventFilter {
+    fn matches(&self, event: &LoggedEvent) -> bool {
+        self.since.is_none_or(|since| event.at_ms >= since)
+            && self.until.is_none_or(|until| event.at_ms < until)
+            && self.r#type.as_ref().is_none_or(|t| *t == event.r#type)
+            && self
+                .device
+                .as_ref()
+                .is_none_or(|d| event.device.as_ref() == Some(d))
+    }
+}
+
+// days since 1970-01-01 -> (year, month, day), see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+fn date(at_ms: u64) -> String {
+    let (year, month, day) = civil_from_days((at_ms / DAY_MS) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// RFC 3339 timestamp in UTC
+pub fn timestamp(at_ms: u64) -> String {
+    let ms = at_ms % DAY_MS;
+    format!(
+        "{}T{:02}:{:02}:{:02}.{:03}Z",
+        date(at_ms),
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+pub const CSV_HEADER: &str = "at_ms,time,device,type,data\r\n";
+
+/// Renders `event` as line of CSV, see `CSV_HEADER`
+pub fn csv_line(event: &LoggedEvent) -> String {
+    let data = match &event.data {
+        serde_json::Value::String(s) => s.clone(),
+        data => data.to_string(),
+    };
+    format!(
+        "{},{},{},{},{}\r\n",
+        event.at_ms,
+        timestamp(event.at_ms),
+        csv_field(event.device.as_deref().unwrap_or_default()),
+        csv_field(&event.r#type),
+        csv_field(&data)
+    )
+}
+
+struct Current {
+    date: String,
+    file: File,
+}
+
+/// Append only log of all published events, stored as one JSON lines file per day
+/// (`events-YYYY-MM-DD.jsonl`, UTC). Files older than `retention_days` are deleted
+/// whenever a new day starts.
+pub struct EventLog {
+    dir: PathBuf,
+    retention_days: u64,
+    current: Mutex<Option<Current>>,
+    redaction: Option<CardIdHasher>,
+}
+
+fn redact_str(value: Option<&mut serde_json::Value>, hasher: &CardIdHasher) {
+    if let Some(value) = value {
+        if let Some(id) = value.as_str() {
+            *value = hasher.redact(id).into();
+        }
+    }
+}
+
+// every string in `value`, e.g. the captures of a serial device
+fn redact_all(value: &mut serde_json::Value, hasher: &CardIdHasher) {
+    match value {
+        serde_json::Value::String(s) => *s = hasher.redact(s),
+        serde_json::Value::Array(values) => values
+            .iter_mut()
+            .for_each(|value| redact_all(value, hasher)),
+        serde_json::Value::Object(values) => values
+            .values_mut()
+            .for_each(|value| redact_all(value, hasher)),
+        _ => {}
+    }
+}
+
+impl EventLog {
+    pub fn open(dir: &Path, retention_days: u64) -> io::Result<EventLog> {
+        std::fs::create_dir_all(dir)?;
+        Ok(EventLog {
+            dir: dir.to_path_buf(),
+            retention_days,
+            current: Mutex::new(None),
+            redaction: None,
+        })
+    }
+
+    /// Store Mete UUIDs only in redacted form. Plain UIDs are expected to be hashed
+    /// already.
+    pub fn with_redaction(mut self, hasher: CardIdHasher) -> EventLog {
+        self.redaction = Some(hasher);
+        self
+    }
+
+    fn redact(&self, r#type: &str, sensitive: bool, data: &mut serde_json::Value) {
+        let hasher = match &self.redaction {
+            Some(hasher) => hasher,
+            None => return,
+        };
+        if sensitive {
+            return redact_all(data, hasher);
+        }
+        match r#type {
+            "nfc-uuid" => redact_str(Some(data), hasher),
+            "nfc-user" => redact_str(data.get_mut("card"), hasher),
+            "session-updated" => {
+                let customer = data.pointer_mut("/session/customer");
+                if let Some(customer) = customer {
+                    if customer["type"] == "mete-uuid" {
+                        redact_str(customer.get_mut("id"), hasher);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Stores `message`, `sensitive` data is redacted like Mete UUIDs
+    pub fn append(&self, device: Option<&str>, message: &Message, sensitive: bool) {
+        let mut event = LoggedEvent {
+            at_ms: unixtime::now_millis(),
+            device: device.map(str::to_string),
+            r#type: message.r#type.clone(),
+            data: message.data.clone(),
+        };
+        self.redact(&event.r#type, sensitive, &mut event.data);
+        if let Err(e) = self.write(&event) {
+            tracing::error!("Error writing event log {}", e);
+        }
+    }
+
+    fn write(&self, event: &LoggedEvent) -> io::Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        let mut current = self.current.lock().unwrap();
+        let date = date(event.at_ms);
+        if current.as_ref().map(|c| &c.date) != Some(&date) {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.dir.join(format!("events-{}.jsonl", date)))?;
+            *current = Some(Current { date, file });
+            self.prune(event.at_ms);
+        }
+        current.as_mut().unwrap().file.write_all(&line)
+    }
+
+    fn files(&self) -> io::Result<Vec<(String, PathBuf)>> {
+        let mut files: Vec<_> = std::fs::read_dir(&self.dir)?
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                let name = path.file_name()?.to_str()?;
+                let date = name.strip_prefix("events-")?.strip_suffix(".jsonl")?;
+                Some((date.to_string(), path))
+            })
+            .collect();
+        // ISO dates sort chronologically
+        files.sort();
+        Ok(files)
+    }
+
+    fn prune(&self, now_ms: u64) {
+        let oldest = date(now_ms.saturating_sub(self.retention_days * DAY_MS));
+        let files = match self.files() {
+            Ok(files) => files,
+            Err(e) => {
+                tracing::error!("Error listing event log {}", e);
+                return;
+            }
+        };
+        for (date, path) in files.into_iter().filter(|(date, _)| *date < oldest) {
+            tracing::info!("Removing event log of {}", date);
+            if let Err(e) = std::fs::remove_file(&path) {
+                tracing::error!("Error removing {:?} {}", path, e);
+            }
+        }
+    }
+
+    /// Logged events matching `filter`, oldest first, by default at most
+    /// `DEFAULT_LIMIT`. This reads the files, so better call it from a blocking task.
+    pub fn query(&self, filter: &EventFilter) -> io::Result<Vec<LoggedEvent>> {
+        let filter = EventFilter {
+            limit: Some(filter.limit.unwrap_or(DEFAULT_LIMIT)),
+            ..filter.clone()
+        };
+        let mut events = Vec::new();
+        self.scan(&filter, |event| {
+            events.push(event);
+            true
+        })?;
+        Ok(events)
+    }
+
+    /// Calls `f` with every logged event matching `filter`, oldest first, until it
+    /// returns `false`. Only one file is read into memory at a time.
+    pub fn scan(
+        &self,
+        filter: &EventFilter,
+        mut f: impl FnMut(LoggedEvent) -> bool,
+    ) -> io::Result<()> {
+        let first = filter.since.map(date);
+        // `until` is exclusive
+        let last = filter.until.map(|until| date(until.saturating_sub(1)));
+        let mut remaining = filter.limit.unwrap_or(usize::MAX);
+        for (date, path) in self.files()? {
+            if matches!(&first, Some(first) if date < *first) {
+                continue;
+            }
+            if matches!(&last, Some(last) if date > *last) {
+                break;
+            }
+            for line in BufReader::new(File::open(path)?).lines() {
+                if remaining == 0 {
+                    return Ok(());
+                }
+                // an interrupted write leaves half a line behind, skip it
+                let event: LoggedEvent = match serde_json::from_str(&line?) {
+                    Ok(event) => event,
+                    Err(_) => continue,
+                };
+                if !filter.matches(&event) {
+                    continue;
+                }
+                remaining -= 1;
+                if !f(event) {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Appends everything `observer` sees to `log` until `cancel` fires
+pub async fn run(log: Arc<EventLog>, mut observer: Observer, cancel: CancellationToken) {
+    loop {
+        let (device, message, sensitive) = tokio::select! {
+            // drain the events before noticing the shutdown, so server-shutdown is logged
+            biased;
+            message = observer.recv_sensitive() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            _ = cancel.cancelled() => break,
+        };
+        let log = log.clone();
+        // one at a time, so the events stay in order
+        let write =
+            tokio::task::spawn_blocking(move || log.append(device.as_deref(), &message, sensitive));
+        if let Err(e) = write.await {
+            tracing::error!("Error writing event log {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_until_and_limit() {
+        let dir = std::env::temp_dir().join(format!("eventlog-test-{}", std::process::id()));
+        let log = EventLog::open(&dir, 100_000).unwrap();
+        // three days, two events each
+        for at_ms in [0, 1, DAY_MS, DAY_MS + 1, 2 * DAY_MS, 2 * DAY_MS + 1] {
+            let event = LoggedEvent {
+                at_ms,
+                device: None,
+                r#type: "barcode".to_string(),
+                data: serde_json::Value::Null,
+            };
+            log.write(&event).unwrap();
+        }
+        let at = |filter: EventFilter| -> Vec<u64> {
+            log.query(&filter)
+                .unwrap()
+                .iter()
+                .map(|e| e.at_ms)
+                .collect()
+        };
+
+        let until = at(EventFilter {
+            since: Some(1),
+            until: Some(DAY_MS + 1),
+            ..Default::default()
+        });
+        assert_eq!(until, [1, DAY_MS]);
+        let limit = at(EventFilter {
+            since: Some(DAY_MS),
+            limit: Some(3),
+            ..Default::default()
+        });
+        assert_eq!(limit, [DAY_MS, DAY_MS + 1, 2 * DAY_MS]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sensitive_data_is_redacted() {
+        let dir = std::env::temp_dir().join(format!("eventlog-redact-{}", std::process::id()));
+        let hasher = CardIdHasher::new(b"secret");
+        let log = EventLog::open(&dir, 90)
+            .unwrap()
+            .with_redaction(hasher.clone());
+        let card = Message {
+            r#type: "card".to_string(),
+            data: serde_json::json!({ "id": "04aabbcc", "slot": 1 }),
+        };
+        log.append(Some("reader"), &card, true);
+        let button = Message {
+            r#type: "spende".to_string(),
+            data: "spende".into(),
+        };
+        log.append(Some("spende"), &button, false);
+
+        let events = log.query(&EventFilter::default()).unwrap();
+        assert_eq!(
+            events[0].data,
+            serde_json::json!({ "id": hasher.redact("04aabbcc"), "slot": 1 })
+        );
+        assert_eq!(events[1].data, "spende");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
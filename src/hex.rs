@@ -0,0 +1,4 @@
+/// Lowercase hex digits of `bytes`, two per byte
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
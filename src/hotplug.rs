@@ -0,0 +1,258 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::io::unix::AsyncFd;
+use tokio::sync::broadcast;
+
+const WATCH_MASK: u32 = libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_TO
+    | libc::IN_MOVED_FROM
+    | libc::IN_ATTRIB
+    | libc::IN_DELETE_SELF;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HotplugEvent {
+    Added(PathBuf),
+    Removed(PathBuf),
+}
+
+struct InotifyFd(RawFd);
+
+impl AsRawFd for InotifyFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for InotifyFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+struct Watches {
+    // watch descriptor -> watched directory
+    dirs: HashMap<i32, PathBuf>,
+    // directories someone is interested in, they might not exist yet
+    wanted: HashSet<PathBuf>,
+}
+
+struct Inner {
+    fd: AsyncFd<InotifyFd>,
+    watches: Mutex<Watches>,
+    events: broadcast::Sender<HotplugEvent>,
+}
+
+/// Device node discovery based on inotify.
+///
+/// Watches the directories containing device nodes (`/dev`, `/dev/input/by-id`, ...)
+/// and broadcasts whenever a node appears or disappears. Directories which don't
+/// exist yet (`/dev/input/by-id` is only created by udev once the first input device
+/// shows up) are picked up as soon as they are created.
+#[derive(Clone)]
+pub struct Hotplug {
+    inner: Arc<Inner>,
+}
+
+impl Hotplug {
+    pub fn new() -> io::Result<Hotplug> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (events, _) = broadcast::channel(64);
+        let inner = Arc::new(Inner {
+            fd: AsyncFd::new(InotifyFd(fd))?,
+            watches: Mutex::new(Watches {
+                dirs: HashMap::new(),
+                wanted: HashSet::new(),
+            }),
+            events,
+        });
+        let reader = inner.clone();
+        tokio::spawn(async move {
+            if let Err(e) = reader.read_events().await {
+                tracing::error!("Error reading hotplug events {}", e);
+            }
+        });
+        Ok(Hotplug { inner })
+    }
+
+    /// Start watching the directory containing `dev` and return a receiver for
+    /// all hotplug events.
+    pub fn watch(&self, dev: &Path) -> broadcast::Receiver<HotplugEvent> {
+        match dev.parent() {
+            Some(dir) => self.watch_dir(dir),
+            None => self.inner.events.subscribe(),
+        }
+    }
+
+    /// Start watching `dir` and return a receiver for all hotplug events.
+    pub fn watch_dir(&self, dir: &Path) -> broadcast::Receiver<HotplugEvent> {
+        let receiver = self.inner.events.subscribe();
+        let mut watches = self.inner.watches.lock().unwrap();
+        watches.wanted.insert(dir.to_path_buf());
+        self.inner.add_watches(&mut watches);
+        receiver
+    }
+}
+
+impl Inner {
+    fn add_watch(&self, watches: &mut Watches, dir: &Path) -> io::Result<()> {
+        if watches.dirs.values().any(|d| d == dir) {
+            return Ok(());
+        }
+        let path = CString::new(dir.as_os_str().as_bytes())?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), path.as_ptr(), WATCH_MASK) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        tracing::debug!("Watching {:?} for hotplug events", dir);
+        watches.dirs.insert(wd, dir.to_path_buf());
+        Ok(())
+    }
+
+    /// Watch all wanted directories. If one doesn't exist (yet) watch the closest
+    /// existing parent so we get notified when it is created.
+    fn add_watches(&self, watches: &mut Watches) {
+        let wanted: Vec<PathBuf> = watches.wanted.iter().cloned().collect();
+        for dir in wanted {
+            let existing = dir.ancestors().find(|d| d.is_dir());
+            if let Some(existing) = existing {
+                if let Err(e) = self.add_watch(watches, existing) {
+                    tracing::error!("Error watching {:?} {}", existing, e);
+                }
+            }
+        }
+    }
+
+    async fn read_events(&self) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let mut guard = self.fd.readable().await?;
+            let r = match guard.try_io(|fd| {
+                let r = unsafe {
+                    libc::read(
+                        fd.as_raw_fd(),
+                        buf.as_mut_ptr() as *mut libc::c_void,
+                        buf.len(),
+                    )
+                };
+                if r < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(r as usize)
+                }
+            }) {
+                Ok(r) => r?,
+                Err(_would_block) => continue,
+            };
+            self.handle_events(&buf[..r]);
+        }
+    }
+
+    fn handle_events(&self, mut buf: &[u8]) {
+        let header_size = std::mem::size_of::<libc::inotify_event>();
+        while buf.len() >= header_size {
+            let event =
+                unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const libc::inotify_event) };
+            let end = header_size + event.len as usize;
+            let name = &buf[header_size..end.min(buf.len())];
+            // the name is padded with NUL bytes
+            let name = OsStr::from_bytes(name.split(|b| *b == 0).next().unwrap_or_default());
+            self.handle_event(&event, name);
+            buf = &buf[end.min(buf.len())..];
+        }
+    }
+
+    fn handle_event(&self, event: &libc::inotify_event, name: &OsStr) {
+        let mut watches = self.watches.lock().unwrap();
+        if event.mask & libc::IN_IGNORED != 0 {
+            // the watched directory is gone. fall back to one of its parents
+            watches.dirs.remove(&event.wd);
+            self.add_watches(&mut watches);
+            return;
+        }
+        let dir = match watches.dirs.get(&event.wd) {
+            Some(dir) => dir.clone(),
+            None => return,
+        };
+        let path = dir.join(name);
+
+        if event.mask & libc::IN_ISDIR != 0 {
+            if event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0
+                && watches.wanted.iter().any(|w| w.starts_with(&path))
+            {
+                self.add_watches(&mut watches);
+                // entries might have been created before our watch was in place
+                for dir in watches.wanted.iter().filter(|w| w.starts_with(&path)) {
+                    for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+                        let _ = self.events.send(HotplugEvent::Added(entry.path()));
+                    }
+                }
+            }
+            return;
+        }
+
+        let event = if event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO | libc::IN_ATTRIB) != 0 {
+            HotplugEvent::Added(path)
+        } else if event.mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0 {
+            HotplugEvent::Removed(path)
+        } else {
+            return;
+        };
+        tracing::debug!("Hotplug event {:?}", event);
+        // nobody listening is fine
+        let _ = self.events.send(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    async fn wait_for(events: &mut broadcast::Receiver<HotplugEvent>, expected: HotplugEvent) {
+        let wait = async {
+            loop {
+                if events.recv().await.unwrap() == expected {
+                    return;
+                }
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .unwrap_or_else(|_| panic!("timeout waiting for {:?}", expected));
+    }
+
+    #[tokio::test]
+    async fn watches_directories_created_later() {
+        let base = std::env::temp_dir().join(format!("hotplug-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir(&base).unwrap();
+        let dir = base.join("input").join("by-id");
+        let dev = dir.join("usb-scanner");
+
+        let hotplug = Hotplug::new().unwrap();
+        let mut events = hotplug.watch(&dev);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(&dev, "").unwrap();
+        wait_for(&mut events, HotplugEvent::Added(dev.clone())).await;
+
+        // the watch is on the directory itself by now
+        let other = dir.join("usb-storno");
+        std::fs::write(&other, "").unwrap();
+        wait_for(&mut events, HotplugEvent::Added(other)).await;
+        std::fs::remove_file(&dev).unwrap();
+        wait_for(&mut events, HotplugEvent::Removed(dev)).await;
+
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+}
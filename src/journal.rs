@@ -0,0 +1,488 @@
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio_util::sync::CancellationToken;
+
+use crate::hex;
+use crate::mete::{MeteClient, MeteError};
+use crate::session::{Customer, Session};
+use crate::supervisor::Backoff;
+use crate::unixtime;
+
+/// A finished purchase waiting to be booked in Mete
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transaction {
+    /// unique id of the purchase
+    pub key: String,
+    pub customer: Customer,
+    /// barcodes
+    pub items: Vec<String>,
+    pub created_at_ms: u64,
+}
+
+impl Transaction {
+    pub fn from_session(session: &Session) -> Transaction {
+        let key: [u8; 16] = rand::thread_rng().gen();
+        Transaction {
+            key: hex::encode(&key),
+            customer: session.customer.clone(),
+            items: session
+                .items
+                .iter()
+                .map(|item| item.barcode.clone())
+                .collect(),
+            created_at_ms: unixtime::now_millis(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+enum Record {
+    Queued {
+        transaction: Transaction,
+    },
+    /// about to book item number `item`, written before the request is sent
+    Booking {
+        key: String,
+        item: usize,
+    },
+    /// the first `items` items have been booked
+    Uploaded {
+        key: String,
+        items: usize,
+    },
+    /// Mete rejected the transaction or it's unclear whether an item was booked, it
+    /// won't be retried
+    Failed {
+        key: String,
+        error: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JournalEntry {
+    #[serde(flatten)]
+    pub transaction: Transaction,
+    /// number of items booked so far
+    pub uploaded: usize,
+    /// failed upload attempts since the start
+    pub attempts: u32,
+    pub error: Option<String>,
+    /// item whose booking request has been sent without knowing the outcome
+    #[serde(skip)]
+    booking: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JournalStatus {
+    pub pending: Vec<JournalEntry>,
+    pub failed: Vec<JournalEntry>,
+}
+
+struct State {
+    file: File,
+    pending: VecDeque<JournalEntry>,
+    failed: Vec<JournalEntry>,
+}
+
+fn append(file: &mut File, record: &Record) -> io::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    // a booked drink must survive a power cut
+    file.sync_data()
+}
+
+/// Durable queue of transactions for Mete, stored as append only JSON lines file.
+///
+/// Transactions are uploaded strictly in order. While Mete is unreachable they stay
+/// pending, transactions Mete rejects are moved aside as failed.
+pub struct Journal {
+    state: Mutex<State>,
+    notify: Notify,
+}
+
+impl Journal {
+    /// Replays the journal at `path` and compacts it to the still relevant records
+    pub fn open(path: &Path) -> Result<Journal, Box<dyn Error>> {
+        let mut pending: VecDeque<JournalEntry> = VecDeque::new();
+        let mut failed = Vec::new();
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let line = line?;
+                    if line.trim().is_empty() {
+                        continue;
+                    }
+                    let record: Record = match serde_json::from_str(&line) {
+                        Ok(record) => record,
+                        Err(e) => {
+                            // most likely the last write was interrupted
+                            tracing::error!("Skipping invalid journal record {:?} {}", line, e);
+                            continue;
+                        }
+                    };
+                    match record {
+                        Record::Queued { transaction } => pending.push_back(JournalEntry {
+                            transaction,
+                            uploaded: 0,
+                            attempts: 0,
+                            error: None,
+                            booking: None,
+                        }),
+                        Record::Booking { key, item } => {
+                            if let Some(entry) =
+                                pending.iter_mut().find(|e| e.transaction.key == key)
+                            {
+                                entry.booking = Some(item);
+                            }
+                        }
+                        Record::Uploaded { key, items } => {
+                            if let Some(entry) =
+                                pending.iter_mut().find(|e| e.transaction.key == key)
+                            {
+                                entry.uploaded = items;
+                                entry.booking = None;
+                            }
+                        }
+                        Record::Failed { key, error } => {
+                            if let Some(i) = pending.iter().position(|e| e.transaction.key == key) {
+                                let mut entry = pending.remove(i).unwrap();
+                                entry.error = Some(error);
+                                failed.push(entry);
+                            }
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        pending.retain(|entry| entry.uploaded < entry.transaction.items.len());
+        // we went down while booking, Mete might or might not have booked the item and
+        // it offers no way to find out. Better let a human check than book it twice.
+        let (interrupted, pending): (VecDeque<_>, VecDeque<_>) = pending
+            .into_iter()
+            .partition(|entry| entry.booking.is_some());
+        for mut entry in interrupted {
+            let item = entry.booking.take().unwrap();
+            tracing::error!(
+                "Transaction {} was interrupted while booking item {}, check Mete",
+                entry.transaction.key,
+                item
+            );
+            entry.error = Some(interrupted_error(&entry.transaction, item));
+            failed.push(entry);
+        }
+
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        for entry in failed.iter().chain(pending.iter()) {
+            append(
+                &mut file,
+                &Record::Queued {
+                    transaction: entry.transaction.clone(),
+                },
+            )?;
+            let key = &entry.transaction.key;
+            if entry.uploaded > 0 {
+                append(
+                    &mut file,
+                    &Record::Uploaded {
+                        key: key.clone(),
+                        items: entry.uploaded,
+                    },
+                )?;
+            }
+            if let Some(error) = &entry.error {
+                append(
+                    &mut file,
+                    &Record::Failed {
+                        key: key.clone(),
+                        error: error.clone(),
+                    },
+                )?;
+            }
+        }
+        std::fs::rename(&tmp, path)?;
+        let file = OpenOptions::new().append(true).open(path)?;
+        if !pending.is_empty() {
+            tracing::info!("{} transactions waiting for upload", pending.len());
+        }
+
+        Ok(Journal {
+            state: Mutex::new(State {
+                file,
+                pending,
+                failed,
+            }),
+            notify: Notify::new(),
+        })
+    }
+
+    pub fn enqueue(&self, transaction: Transaction) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        append(
+            &mut state.file,
+            &Record::Queued {
+                transaction: transaction.clone(),
+            },
+        )?;
+        state.pending.push_back(JournalEntry {
+            transaction,
+            uploaded: 0,
+            attempts: 0,
+            error: None,
+            booking: None,
+        });
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    pub fn status(&self) -> JournalStatus {
+        let state = self.state.lock().unwrap();
+        JournalStatus {
+            pending: state.pending.iter().cloned().collect(),
+            failed: state.failed.clone(),
+        }
+    }
+
+    fn head(&self) -> Option<JournalEntry> {
+        self.state.lock().unwrap().pending.front().cloned()
+    }
+
+    fn booking(&self, key: &str, item: usize) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        append(
+            &mut state.file,
+            &Record::Booking {
+                key: key.to_string(),
+                item,
+            },
+        )
+    }
+
+    fn uploaded(&self, key: &str, items: usize) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        append(
+            &mut state.file,
+            &Record::Uploaded {
+                key: key.to_string(),
+                items,
+            },
+        )?;
+        if let Some(i) = state.pending.iter().position(|e| e.transaction.key == key) {
+            state.pending[i].uploaded = items;
+            if items >= state.pending[i].transaction.items.len() {
+                state.pending.remove(i);
+            }
+        }
+        Ok(())
+    }
+
+    fn attempt_failed(&self, key: &str, error: &MeteError) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(entry) = state.pending.iter_mut().find(|e| e.transaction.key == key) {
+            entry.attempts += 1;
+            entry.error = Some(error.to_string());
+        }
+    }
+
+    fn failed(&self, key: &str, error: String) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        append(
+            &mut state.file,
+            &Record::Failed {
+                key: key.to_string(),
+                error: error.clone(),
+            },
+        )?;
+        if let Some(i) = state.pending.iter().position(|e| e.transaction.key == key) {
+            let mut entry = state.pending.remove(i).unwrap();
+            entry.attempts += 1;
+            entry.error = Some(error);
+            state.failed.push(entry);
+        }
+        Ok(())
+    }
+}
+
+fn interrupted_error(transaction: &Transaction, item: usize) -> String {
+    format!(
+        "item {} ({}) might have been booked, check Mete before booking the rest",
+        item, transaction.items[item]
+    )
+}
+
+enum UploadError {
+    Mete(MeteError),
+    /// the booking request of an item might have reached Mete
+    Uncertain(usize, MeteError),
+    Journal(io::Error),
+}
+
+impl From<MeteError> for UploadError {
+    fn from(e: MeteError) -> UploadError {
+        UploadError::Mete(e)
+    }
+}
+
+impl From<io::Error> for UploadError {
+    fn from(e: io::Error) -> UploadError {
+        UploadError::Journal(e)
+    }
+}
+
+// every write is synced to disk, keep that off the runtime
+async fn write(
+    journal: &Arc<Journal>,
+    f: impl FnOnce(&Journal) -> io::Result<()> + Send + 'static,
+) -> io::Result<()> {
+    let journal = journal.clone();
+    tokio::task::spawn_blocking(move || f(&journal))
+        .await
+        .map_err(io::Error::other)?
+}
+
+async fn upload_entry(
+    journal: &Arc<Journal>,
+    mete: &MeteClient,
+    entry: &JournalEntry,
+) -> Result<(), UploadError> {
+    let key = &entry.transaction.key;
+    let card = match &entry.transaction.customer {
+        Customer::MeteUuid(id) | Customer::Plain(id) => id,
+    };
+    let user = mete
+        .user(card)
+        .await?
+        .value
+        .ok_or(MeteError::Status(hyper::StatusCode::NOT_FOUND))?;
+    let items = &entry.transaction.items;
+    for (i, barcode) in items.iter().enumerate().skip(entry.uploaded) {
+        let booking = key.clone();
+        write(journal, move |journal| journal.booking(&booking, i)).await?;
+        match mete.buy(user.id, barcode).await {
+            Ok(()) => {}
+            Err(e) if e.maybe_processed() => return Err(UploadError::Uncertain(i, e)),
+            Err(e) => return Err(e.into()),
+        }
+        let uploaded = key.clone();
+        write(journal, move |journal| journal.uploaded(&uploaded, i + 1)).await?;
+    }
+    if items.is_empty() {
+        let uploaded = key.clone();
+        write(journal, move |journal| journal.uploaded(&uploaded, 0)).await?;
+    }
+    Ok(())
+}
+
+/// Upload the queued transactions in order, waiting for Mete whenever it is unreachable
+pub async fn upload(journal: Arc<Journal>, mete: Arc<MeteClient>, cancel: CancellationToken) {
+    let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(300));
+    loop {
+        let entry = match journal.head() {
+            Some(entry) => entry,
+            None => {
+                tokio::select! {
+                    _ = journal.notify.notified() => continue,
+                    _ = cancel.cancelled() => return,
+                }
+            }
+        };
+        let key = &entry.transaction.key;
+        let error = match upload_entry(&journal, &mete, &entry).await {
+            Ok(()) => {
+                tracing::info!("Uploaded transaction {}", key);
+                backoff.reset();
+                continue;
+            }
+            Err(e) => e,
+        };
+        match error {
+            UploadError::Mete(e) if !e.is_transient() => {
+                tracing::error!("Mete rejected transaction {}: {}", key, e);
+                let (key, error) = (key.clone(), e.to_string());
+                if let Err(e) = write(&journal, move |journal| journal.failed(&key, error)).await {
+                    tracing::error!("Error writing journal {}", e);
+                }
+            }
+            UploadError::Uncertain(item, e) => {
+                tracing::error!(
+                    "Transaction {} failed while booking item {}: {}",
+                    key,
+                    item,
+                    e
+                );
+                let error = format!("{}: {}", interrupted_error(&entry.transaction, item), e);
+                let key = key.clone();
+                if let Err(e) = write(&journal, move |journal| journal.failed(&key, error)).await {
+                    tracing::error!("Error writing journal {}", e);
+                }
+            }
+            UploadError::Mete(e) => {
+                let delay = backoff.next_delay();
+                tracing::warn!(
+                    "Error uploading transaction {}, retrying in {:?}: {}",
+                    key,
+                    delay,
+                    e
+                );
+                journal.attempt_failed(key, &e);
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {},
+                    _ = cancel.cancelled() => return,
+                }
+            }
+            // the journal itself is broken, nothing we can do but try again later
+            UploadError::Journal(e) => {
+                tracing::error!("Error writing journal {}", e);
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff.next_delay()) => {},
+                    _ = cancel.cancelled() => return,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupted_booking_is_not_retried() {
+        let path = std::env::temp_dir().join(format!("journal-test-{}.jsonl", std::process::id()));
+        let records = [
+            r#"{"op":"queued","transaction":{"key":"a","customer":{"type":"plain","id":"01"},"items":["1","2"],"created_at_ms":0}}"#,
+            r#"{"op":"booking","key":"a","item":0}"#,
+            r#"{"op":"uploaded","key":"a","items":1}"#,
+            r#"{"op":"booking","key":"a","item":1}"#,
+            r#"{"op":"queued","transaction":{"key":"b","customer":{"type":"plain","id":"01"},"items":["1"],"created_at_ms":0}}"#,
+            r#"{"op":"booking","key":"b","item":0}"#,
+            r#"{"op":"uploaded","key":"b","items":1}"#,
+            r#"{"op":"queued","transaction":{"key":"c","customer":{"type":"plain","id":"01"},"items":["1"],"created_at_ms":0}}"#,
+        ];
+        std::fs::write(&path, records.join("\n")).unwrap();
+
+        for _ in 0..2 {
+            // the compacted journal replays the same
+            let status = Journal::open(&path).unwrap().status();
+            let pending: Vec<_> = status.pending.iter().map(|e| &e.transaction.key).collect();
+            assert_eq!(pending, ["c"]);
+            assert_eq!(status.failed.len(), 1);
+            assert_eq!(status.failed[0].transaction.key, "a");
+            assert_eq!(status.failed[0].uploaded, 1);
+            assert!(status.failed[0].error.is_some());
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+}
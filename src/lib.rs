@@ -1,4 +1,21 @@
 pub mod barcodeservice;
+pub mod cardid;
+pub mod dbus;
+pub mod eventbus;
+pub mod eventlog;
+pub mod hex;
+pub mod hotplug;
+pub mod journal;
+pub mod mete;
+pub mod metecache;
 pub mod middlewares;
+pub mod mqtt;
+pub mod nfcfeedback;
 pub mod nfcservice;
-pub mod stornoservice;
\ No newline at end of file
+pub mod serial;
+pub mod serialservice;
+pub mod session;
+pub mod stornoservice;
+pub mod supervisor;
+pub mod unixtime;
+pub mod webhook;
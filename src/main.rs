@@ -1,111 +1,399 @@
-use axum::extract::State;
-use axum::http::{HeaderValue, Method};
+use async_stream::stream;
+use axum::body::StreamBody;
+use axum::extract::{ConnectInfo, FromRef, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, Method, StatusCode};
 use axum::middleware;
 use axum::response::sse::{Event, Sse};
-use axum::routing::get;
-use axum::Router;
-use serde::Serialize;
-use std::collections::HashMap;
+use axum::routing::{delete, get, post};
+use axum::{Json, Router};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
 use std::error::Error;
 use std::net::SocketAddr;
-use std::sync::atomic::Ordering;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use std::{convert::Infallible, sync::atomic::AtomicUsize};
-use tokio::sync::mpsc;
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch};
+use tokio::time::Instant;
+use tokio_stream::wrappers::ReceiverStream;
 use tokio_stream::{Stream, StreamExt as _};
+use tokio_util::sync::CancellationToken;
 use tower::ServiceBuilder;
 use tower_http::cors::CorsLayer;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+use getraenkekassengeraete::barcodeservice::{BarcodeSource, DeviceMatcher};
+use getraenkekassengeraete::cardid::{self, CardIdHasher, Redacted};
+use getraenkekassengeraete::dbus;
+use getraenkekassengeraete::eventbus::{
+    EventBus, LeaseError, LeaseStatus, Message, OverflowPolicy, SubscriberInfo, SubscriberStatus,
+};
+use getraenkekassengeraete::eventlog::{self, EventFilter, EventLog, LoggedEvent};
+use getraenkekassengeraete::hex;
+use getraenkekassengeraete::hotplug::Hotplug;
+use getraenkekassengeraete::journal::{self, Journal, JournalStatus, Transaction};
+use getraenkekassengeraete::mete::{Lookup, MeteClient, MeteError};
+use getraenkekassengeraete::metecache::OfflineCache;
 use getraenkekassengeraete::middlewares::force_local_request;
-use getraenkekassengeraete::{barcodeservice, nfcservice, stornoservice};
+use getraenkekassengeraete::mqtt::{self, MqttConfig};
+use getraenkekassengeraete::nfcfeedback::{self, FeedbackConfig};
+use getraenkekassengeraete::nfcservice::NfcEvent;
+use getraenkekassengeraete::serial::{CommandError, SerialCommands, SerialConfig};
+use getraenkekassengeraete::serialservice::SerialEvent;
+use getraenkekassengeraete::session::{Session, SessionInput, SessionTracker, SessionUpdate};
+use getraenkekassengeraete::stornoservice::StornoEvent;
+use getraenkekassengeraete::supervisor::{Backoff, DeviceStatus, StatusBoard, Supervisors};
+use getraenkekassengeraete::webhook::{self, FailedDelivery, FailureStore};
+use getraenkekassengeraete::{barcodeservice, nfcservice, serialservice, stornoservice};
 
-/// Our global unique client id counter.
-static NEXT_CLIENT_ID: AtomicUsize = AtomicUsize::new(1);
+#[derive(Clone, FromRef)]
+struct AppState {
+    events: EventBus,
+    devices: StatusBoard,
+    commands: SerialCommands,
+    feedback: Option<Arc<FeedbackConfig>>,
+    sessions: Option<SessionTracker>,
+    journal: Option<Arc<Journal>>,
+    event_log: Option<Arc<EventLog>>,
+    webhook_failures: Option<Arc<FailureStore>>,
+}
 
-// this is a generic type right now and I don't like it but I fail
-// to send two different messages because of rust n00bness
-#[derive(Debug, Clone, Serialize)]
-struct Message {
-    r#type: String,
-    id: String,
+fn barcode_source() -> Result<BarcodeSource, Box<dyn Error>> {
+    if let Ok(dev) = std::env::var("BARCODE_DEVICE") {
+        return Ok(BarcodeSource::Device(dev.into()));
+    }
+    let mut matcher = DeviceMatcher {
+        usb_id: std::env::var("BARCODE_USB_ID")
+            .ok()
+            .map(|id| id.parse())
+            .transpose()?,
+        name: std::env::var("BARCODE_NAME")
+            .ok()
+            .map(|name| Regex::new(&name))
+            .transpose()?,
+        phys: std::env::var("BARCODE_PHYS").ok(),
+    };
+    if matcher.is_empty() {
+        // the scanner we have been using all along
+        matcher.name = Some(Regex::new("^Newtologic")?);
+    }
+    Ok(BarcodeSource::Match(matcher))
 }
 
-/// Our state of currently connected users.
-///
-/// - Key is their id
-/// - Value is a sender of `Message`
-type Clients = Arc<Mutex<HashMap<usize, mpsc::UnboundedSender<Message>>>>;
+/// Line settings from `<PREFIX>_BAUD`, `<PREFIX>_DATA_BITS`, `<PREFIX>_PARITY`,
+/// `<PREFIX>_STOP_BITS` and `<PREFIX>_FLOW_CONTROL`
+fn serial_config(prefix: &str) -> Result<SerialConfig, Box<dyn Error>> {
+    let var = |name: &str| std::env::var(format!("{}_{}", prefix, name)).ok();
+    let mut config = SerialConfig::default();
+    if let Some(baud) = var("BAUD") {
+        config.baud = baud.parse()?;
+    }
+    if let Some(data_bits) = var("DATA_BITS") {
+        config.data_bits = data_bits.parse()?;
+    }
+    if let Some(parity) = var("PARITY") {
+        config.parity = parity.parse()?;
+    }
+    if let Some(stop_bits) = var("STOP_BITS") {
+        config.stop_bits = stop_bits.parse()?;
+    }
+    if let Some(flow_control) = var("FLOW_CONTROL") {
+        config.flow_control = flow_control.parse()?;
+    }
+    Ok(config)
+}
+
+fn env_millis(name: &str, default: u64) -> Result<Duration, Box<dyn Error>> {
+    let millis = match std::env::var(name) {
+        Ok(var) => var.parse::<u64>()?,
+        Err(_) => default,
+    };
+    Ok(Duration::from_millis(millis))
+}
+
+fn env_fraction(name: &str, default: f64) -> Result<f64, Box<dyn Error>> {
+    let fraction = match std::env::var(name) {
+        Ok(var) => var.parse::<f64>()?,
+        Err(_) => default,
+    };
+    // also rules out NaN
+    if !(0.0..=1.0).contains(&fraction) {
+        return Err(format!("Invalid {} {}, expected 0.0 - 1.0", name, fraction).into());
+    }
+    Ok(fraction)
+}
+
+fn publish_session_update(events: &EventBus, update: SessionUpdate) {
+    events.publish(Message {
+        r#type: "session-updated".to_string(),
+        data: serde_json::to_value(update).unwrap(),
+    });
+}
+
+fn enriched<T: Serialize>(
+    key: &str,
+    value: String,
+    field: &str,
+    result: Result<Lookup<T>, MeteError>,
+) -> serde_json::Value {
+    match result {
+        Ok(found) => serde_json::json!({ key: value, field: found.value, "source": found.source }),
+        Err(e) => {
+            if key == "card" {
+                tracing::warn!("Error looking up {} {} {}", key, Redacted(&value), e);
+            } else {
+                tracing::warn!("Error looking up {} {} {}", key, value, e);
+            }
+            serde_json::json!({ key: value, field: null, "error": e.to_string() })
+        }
+    }
+}
+
+/// Look up the drink of a barcode or the user of a card in the background and
+/// send the result as separate event
+fn enrich(mete: &Arc<MeteClient>, events: &EventBus, device: &str, message: &Message) {
+    let value = match message.data.as_str() {
+        Some(value) => value.to_string(),
+        None => return,
+    };
+    let r#type = message.r#type.clone();
+    if !matches!(r#type.as_str(), "barcode" | "nfc-uuid" | "nfc-plain") {
+        return;
+    }
+    let mete = mete.clone();
+    let events = events.clone();
+    let device = device.to_string();
+    tokio::spawn(async move {
+        let message = if r#type == "barcode" {
+            let result = mete.drink(&value).await;
+            Message {
+                r#type: "barcode-drink".to_string(),
+                data: enriched("barcode", value, "drink", result),
+            }
+        } else {
+            let result = mete.user(&value).await;
+            Message {
+                r#type: "nfc-user".to_string(),
+                data: enriched("card", value, "user", result),
+            }
+        };
+        events.publish_from(&device, message);
+    });
+}
+
+/// Keep the offline cache up to date while Mete is reachable
+async fn sync_mete(mete: Arc<MeteClient>, interval: Duration, cancel: CancellationToken) {
+    let mut backoff = Backoff::new(Duration::from_secs(5), interval);
+    loop {
+        let delay = match mete.sync().await {
+            Ok(count) => {
+                tracing::info!("Synced {} barcodes from Mete", count);
+                backoff.reset();
+                interval
+            }
+            Err(e) => {
+                tracing::warn!("Error syncing offline cache {}", e);
+                backoff.next_delay()
+            }
+        };
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {},
+            _ = cancel.cancelled() => return,
+        }
+    }
+}
+
+/// Resolves once the open session got closed, never without sessions
+async fn session_closed(open: &mut Option<watch::Receiver<Option<u64>>>) {
+    if let Some(open) = open {
+        while open.changed().await.is_ok() {
+            if open.borrow_and_update().is_none() {
+                return;
+            }
+        }
+    }
+    std::future::pending().await
+}
+
+/// Everything happening with device events besides forwarding them
+struct Processing {
+    events: EventBus,
+    sessions: Option<SessionTracker>,
+    idle_timeout: Option<Duration>,
+    mete: Option<Arc<MeteClient>>,
+    cancel: CancellationToken,
+}
 
 async fn consume_device_events(
-    clients: Clients,
-    nfc_stream: impl Stream<Item = Option<nfcservice::CardDetail>>,
+    processing: Processing,
+    nfc_stream: impl Stream<Item = NfcEvent>,
     barcode_stream: impl Stream<Item = String>,
-    storno_stream: impl Stream<Item = ()>,
+    storno_stream: impl Stream<Item = StornoEvent>,
+    serial_stream: impl Stream<Item = SerialEvent>,
 ) {
+    let Processing {
+        events,
+        sessions,
+        idle_timeout,
+        mete,
+        cancel,
+    } = processing;
     tokio::pin!(nfc_stream);
     tokio::pin!(barcode_stream);
     tokio::pin!(storno_stream);
+    tokio::pin!(serial_stream);
+    // armed by an nfc identification, pushed back by every following event feeding the session
+    let mut idle_deadline: Option<Instant> = None;
+    let mut open_session = sessions.as_ref().map(SessionTracker::watch);
     loop {
-        let message = tokio::select! {
-            nfc = nfc_stream.next() => {
+        // marked by the serial device config
+        let mut sensitive = false;
+        // streams only end once their device has been shut down
+        let (device, message) = tokio::select! {
+            Some(nfc) = nfc_stream.next() => {
                 tracing::debug!("NFC Event: {:?}", nfc);
-                // too much nesting :S
-                // unsure why there is another option
-                if nfc.is_none() {
-                    continue;
-                }
-                let nfc = nfc.unwrap();
-                match nfc {
-                    None => Message{
+                ("nfc".to_string(), match nfc {
+                    NfcEvent::Removed => Message{
+                        r#type: "nfc-removed".to_string(),
+                        data: "".into(),
+                    },
+                    NfcEvent::Card(None) => Message{
                         r#type: "nfc-invalid".to_string(),
-                        id: String::new(),
+                        data: "".into(),
                     },
-                    Some(card_detail) => {
+                    NfcEvent::Card(Some(card_detail)) => {
                         match card_detail {
                             nfcservice::CardDetail::MeteUuid(uuid) => {
                                 Message{
                                 r#type: "nfc-uuid".to_string(),
-                                id: uuid,
+                                data: uuid.into(),
                             }},
                             nfcservice::CardDetail::Plain(uid) => {
                                 Message{
                                 r#type: "nfc-plain".to_string(),
-                                id: uid.iter().map(|x| format!("{:02x}", x)).collect::<String>(),
+                                data: hex::encode(&uid).into(),
                             }},
                         }
                     }
-                }
+                })
             },
-            barcode = barcode_stream.next() => {
+            Some(barcode) = barcode_stream.next() => {
                 tracing::debug!("Barcode Event: {:?}", barcode);
-                if barcode.is_none() {
-                    continue;
-                }
-                let barcode = barcode.unwrap();
-                Message {
+                ("barcode".to_string(), Message {
                     r#type: "barcode".to_string(),
-                    id: barcode,
-                }
+                    data: barcode.into(),
+                })
             },
-            storno = storno_stream.next() => {
+            Some(storno) = storno_stream.next() => {
                 tracing::debug!("Storno Event: {:?}", storno);
-                if storno.is_none() {
-                    continue;
+                if storno != StornoEvent::Pressed {
+                    // frontends predating press/release/hold only know this one, which
+                    // was sent on release
+                    events.publish_from("storno", Message {
+                        r#type: "storno".to_string(),
+                        data: "".into(),
+                    });
                 }
-                Message {
-                    r#type: "storno".to_string(),
-                    id: String::from(""),
+                ("storno".to_string(), match storno {
+                    StornoEvent::Pressed => Message {
+                        r#type: "storno-pressed".to_string(),
+                        data: "".into(),
+                    },
+                    StornoEvent::Released => Message {
+                        r#type: "storno-released".to_string(),
+                        data: "".into(),
+                    },
+                    StornoEvent::Held(duration) => Message {
+                        r#type: "storno-held".to_string(),
+                        data: serde_json::json!({ "duration_ms": duration.as_millis() as u64 }),
+                    },
+                })
+            },
+            Some(serial) = serial_stream.next() => {
+                if cardid::logs_redacted() && serial.redact {
+                    tracing::debug!("Serial Event: {} from {}", serial.event, serial.device);
+                } else {
+                    tracing::debug!("Serial Event: {:?}", serial);
                 }
-            }
+                sensitive = serial.redact;
+                (serial.device, Message {
+                    r#type: serial.event,
+                    data: serial.data,
+                })
+            },
+            _ = tokio::time::sleep_until(idle_deadline.unwrap_or_else(Instant::now)),
+                if idle_deadline.is_some() => {
+                idle_deadline = None;
+                tracing::info!("No device events for a while, logging out");
+                events.publish(Message {
+                    r#type: "session-timeout".to_string(),
+                    data: "".into(),
+                });
+                let update = sessions
+                    .as_ref()
+                    .and_then(|sessions| sessions.handle(SessionInput::Timeout, SystemTime::now()));
+                if let Some(update) = update {
+                    publish_session_update(&events, update);
+                }
+                continue;
+            },
+            _ = session_closed(&mut open_session), if idle_deadline.is_some() => {
+                // e.g. checked out, nobody left to log out
+                idle_deadline = None;
+                continue;
+            },
+            // an open session would keep the loop alive after the devices are gone
+            _ = cancel.cancelled() => return,
+            else => return,
         };
-        clients.lock().unwrap().retain(move |_, tx| {
-            // If not `is_ok`, the SSE stream is gone, and so don't retain
-            tx.send(message.clone()).is_ok()
-        });
+        let input = SessionInput::from_message(&message);
+        // leased devices are busy with something else than the purchase
+        let leased = if sensitive {
+            events.publish_sensitive(&device, message.clone())
+        } else {
+            events.publish_from(&device, message.clone())
+        }
+        .is_some();
+        // only now, the lookup result must not overtake the event it belongs to
+        if let Some(mete) = &mete {
+            enrich(mete, &events, &device, &message);
+        }
+        let input = match input {
+            Some(input) if !leased => input,
+            _ => continue,
+        };
+        if let Some(idle_timeout) = idle_timeout {
+            if matches!(input, SessionInput::Identified(_)) || idle_deadline.is_some() {
+                idle_deadline = Some(Instant::now() + idle_timeout);
+            }
+        }
+        if let Some(sessions) = &sessions {
+            if let Some(update) = sessions.handle(input, SystemTime::now()) {
+                publish_session_update(&events, update);
+            }
+        }
+    }
+}
+
+async fn shutdown_signal() -> Result<(), Box<dyn Error>> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        r = tokio::signal::ctrl_c() => r?,
+        _ = terminate.recv() => {},
     }
+    Ok(())
+}
+
+/// Send a last event to all clients and close their streams
+fn disconnect_clients(events: &EventBus) {
+    events.publish(Message {
+        r#type: "server-shutdown".to_string(),
+        data: "".into(),
+    });
+    events.close();
 }
 
 #[tokio::main]
@@ -117,20 +405,249 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    // Keep track of all connected clients, key is usize, value
-    // is an event stream sender.
-    let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
-    let cloned_clients = clients.clone();
-
-    let nfc_stream = nfcservice::run()?;
-    // hardcoded for now
-    let barcode_stream =
-        barcodeservice::run("/dev/input/by-id/usb-Newtologic_NT4010S_XXXXXX-event-kbd");
-    let storno_stream = stornoservice::run("/dev/stornoschluessel");
+    // Fan out device events to all connected clients
+    let events = EventBus::new(
+        match std::env::var("CLIENT_QUEUE_SIZE") {
+            Ok(var) => var.parse()?,
+            Err(_) => 64,
+        },
+        match std::env::var("CLIENT_OVERFLOW") {
+            Ok(var) => var.parse()?,
+            Err(_) => OverflowPolicy::DropOldest,
+        },
+    )
+    .with_exclusive_consumers(match std::env::var("EXCLUSIVE_CONSUMERS") {
+        Ok(var) => var.parse()?,
+        Err(_) => false,
+    });
+    // plain UIDs are replaced by their HMAC, UUIDs are redacted in logs
+    let card_id_hasher = std::env::var("CARD_ID_SECRET")
+        .ok()
+        .map(|secret| CardIdHasher::new(secret.as_bytes()));
+    if let Some(hasher) = &card_id_hasher {
+        hasher.clone().redact_logs();
+    }
+    let event_log = match std::env::var("EVENT_LOG") {
+        Ok(dir) => {
+            let mut event_log = EventLog::open(
+                Path::new(&dir),
+                match std::env::var("EVENT_LOG_RETENTION_DAYS") {
+                    Ok(var) => var.parse()?,
+                    Err(_) => 90,
+                },
+            )?;
+            if let Some(hasher) = &card_id_hasher {
+                event_log = event_log.with_redaction(hasher.clone());
+            }
+            Some(Arc::new(event_log))
+        }
+        Err(_) => None,
+    };
+    let cloned_events = events.clone();
 
-    tokio::spawn(async move {
-        consume_device_events(cloned_clients, nfc_stream, barcode_stream, storno_stream).await;
+    let backoff = Backoff::new(
+        env_millis("RECONNECT_DELAY_MIN_MS", 1000)?,
+        env_millis("RECONNECT_DELAY_MAX_MS", 4000)?,
+    )
+    .with_factor(match std::env::var("RECONNECT_BACKOFF_FACTOR") {
+        Ok(var) => var.parse()?,
+        Err(_) => 2,
+    })
+    .with_jitter(env_fraction("RECONNECT_JITTER", 0.2)?);
+    let cancel = CancellationToken::new();
+    // written off the bus, a slow disk must not hold up publishing
+    let event_log_writer = event_log.as_ref().map(|event_log| {
+        tokio::spawn(eventlog::run(
+            event_log.clone(),
+            events.observe(),
+            cancel.clone(),
+        ))
     });
+    let devices = StatusBoard::default();
+    let hotplug = match Hotplug::new() {
+        Ok(hotplug) => Some(hotplug),
+        Err(e) => {
+            tracing::warn!(
+                "Hotplug detection unavailable, polling devices instead: {}",
+                e
+            );
+            None
+        }
+    };
+    let supervisors = Supervisors::new(backoff, cancel.clone(), devices.clone(), hotplug);
+
+    let feedback = match std::env::var("NFC_FEEDBACK") {
+        Ok(var) if var == "off" => None,
+        Ok(path) => Some(FeedbackConfig::load(Path::new(&path))?),
+        Err(_) => Some(FeedbackConfig::default()),
+    };
+    let nfc_stream = nfcservice::run(
+        supervisors.supervisor("nfc"),
+        feedback.clone(),
+        card_id_hasher,
+    )?;
+    let barcode_stream = barcodeservice::run(barcode_source()?, supervisors.clone());
+    let storno_dev = Path::new("/dev/stornoschluessel");
+    let commands = SerialCommands::default();
+    let storno_stream = stornoservice::run(
+        storno_dev,
+        serial_config("STORNO")?,
+        env_millis("STORNO_HOLD_MS", 1000)?,
+        supervisors.device_supervisor("storno", storno_dev),
+        &commands,
+    );
+
+    let serial_devices = match std::env::var("SERIAL_DEVICES") {
+        Ok(path) => serialservice::load_config(Path::new(&path))?,
+        Err(_) => Vec::new(),
+    };
+    let serial_stream = futures::stream::select_all(serial_devices.into_iter().map(|config| {
+        let supervisor = supervisors.device_supervisor(&config.name, &config.device);
+        Box::pin(serialservice::run(config, supervisor, &commands))
+    }));
+
+    let sessions = match std::env::var("SESSIONS") {
+        Ok(var) if var.parse()? => Some(SessionTracker::new(
+            match std::env::var("SESSION_CLOSE_ON_CARD_REMOVAL") {
+                Ok(var) => var.parse()?,
+                Err(_) => true,
+            },
+        )),
+        _ => None,
+    };
+    let cloned_sessions = sessions.clone();
+    let idle_timeout = match std::env::var("IDLE_TIMEOUT_MS") {
+        Ok(var) => Some(Duration::from_millis(var.parse()?)),
+        Err(_) => None,
+    };
+    let mete = match std::env::var("METE_URL") {
+        Ok(url) => {
+            if cardid::logs_redacted() {
+                tracing::info!(
+                    "CARD_ID_SECRET is set, plain cards are looked up in Mete by their hashed id"
+                );
+            }
+            let mut mete = MeteClient::new(
+                &url,
+                env_millis("METE_TIMEOUT_MS", 3000)?,
+                env_millis("METE_CACHE_TTL_MS", 300000)?,
+            )?;
+            let offline = std::env::var("METE_OFFLINE_CACHE").ok();
+            if let Some(path) = &offline {
+                mete = mete.with_offline_cache(OfflineCache::load(Path::new(path))?);
+            }
+            let mete = Arc::new(mete);
+            if offline.is_some() {
+                let interval = env_millis("METE_SYNC_INTERVAL_MS", 600000)?;
+                tokio::spawn(sync_mete(mete.clone(), interval, cancel.clone()));
+            }
+            Some(mete)
+        }
+        Err(_) => None,
+    };
+
+    let journal = match (std::env::var("TRANSACTION_JOURNAL"), &mete, &sessions) {
+        (Ok(path), Some(mete), Some(_)) => {
+            let journal = Arc::new(Journal::open(Path::new(&path))?);
+            tokio::spawn(journal::upload(
+                journal.clone(),
+                mete.clone(),
+                cancel.clone(),
+            ));
+            Some(journal)
+        }
+        (Ok(_), _, _) => {
+            tracing::warn!("TRANSACTION_JOURNAL requires METE_URL and SESSIONS, ignoring");
+            None
+        }
+        (Err(_), _, _) => None,
+    };
+
+    let mqtt = match std::env::var("MQTT_HOST") {
+        Ok(host) => {
+            let mut config = MqttConfig::new(
+                host,
+                match std::env::var("MQTT_PORT") {
+                    Ok(var) => var.parse()?,
+                    Err(_) => 1883,
+                },
+            );
+            if let Ok(client_id) = std::env::var("MQTT_CLIENT_ID") {
+                config.client_id = client_id;
+            }
+            if let Ok(username) = std::env::var("MQTT_USERNAME") {
+                config.credentials =
+                    Some((username, std::env::var("MQTT_PASSWORD").unwrap_or_default()));
+            }
+            if let Ok(topic) = std::env::var("MQTT_TOPIC") {
+                config.topic = topic;
+            }
+            if let Ok(topic) = std::env::var("MQTT_STATUS_TOPIC") {
+                config.status_topic = topic;
+            }
+            if let Ok(qos) = std::env::var("MQTT_QOS") {
+                config.qos = mqtt::parse_qos(&qos)?;
+            }
+            if let Ok(retain) = std::env::var("MQTT_RETAIN") {
+                config.retain = retain.parse()?;
+            }
+            Some(tokio::spawn(mqtt::run(
+                config,
+                events.clone(),
+                devices.clone(),
+                cancel.clone(),
+            )))
+        }
+        Err(_) => None,
+    };
+
+    // failed deliveries are kept by default, nobody would notice them otherwise
+    let webhook_failures = match std::env::var("WEBHOOK_FAILURES") {
+        Ok(path) => Some(PathBuf::from(path)),
+        Err(_) if std::env::var_os("WEBHOOKS").is_some() => {
+            let dir = PathBuf::from(
+                std::env::var("DATA_DIR")
+                    .unwrap_or_else(|_| "/var/lib/getraenkekassengeraete".to_string()),
+            );
+            std::fs::create_dir_all(&dir)?;
+            Some(dir.join("webhook-failures.jsonl"))
+        }
+        Err(_) => None,
+    };
+    let webhook_failures = match webhook_failures {
+        Some(path) => Some(Arc::new(FailureStore::open(&path)?)),
+        None => None,
+    };
+    let webhooks = match std::env::var("WEBHOOKS") {
+        Ok(path) => Some(webhook::start(
+            webhook::load_config(Path::new(&path))?,
+            webhook_failures.clone(),
+            &events,
+            cancel.clone(),
+        )?),
+        Err(_) => None,
+    };
+
+    let dbus = match std::env::var("DBUS") {
+        Ok(bus) => Some(dbus::start(bus.parse()?, &events, devices.clone(), cancel.clone()).await?),
+        Err(_) => None,
+    };
+
+    let device_events = tokio::spawn(consume_device_events(
+        Processing {
+            events: cloned_events,
+            sessions: cloned_sessions,
+            idle_timeout,
+            mete,
+            cancel: cancel.clone(),
+        },
+        nfc_stream,
+        barcode_stream,
+        storno_stream,
+        serial_stream,
+    ));
+
+    let shutdown_timeout = env_millis("SHUTDOWN_TIMEOUT_MS", 5000)?;
 
     let allow_origin = std::env::var("ALLOW_ORIGIN")
         .ok()
@@ -139,7 +656,32 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // build our application with a route
     let app = Router::new()
         .route("/", get(cashier_event_stream))
-        .with_state(clients)
+        .route("/devices", get(device_status))
+        .route("/clients", get(client_status))
+        .route("/clients/:id", delete(disconnect_client))
+        .route(
+            "/clients/:id/leases",
+            post(lease_devices).delete(release_devices),
+        )
+        .route("/leases", get(lease_status))
+        .route("/session", get(session_status))
+        .route("/session/checkout", post(checkout))
+        .route("/transactions", get(transaction_status))
+        .route("/events", get(query_events))
+        .route("/events.csv", get(export_events))
+        .route("/webhooks/failures", get(webhook_failures_status))
+        .route("/devices/:name/commands", post(device_command))
+        .route("/devices/nfc/feedback", post(nfc_feedback))
+        .with_state(AppState {
+            events: events.clone(),
+            devices,
+            commands,
+            feedback: feedback.map(Arc::new),
+            sessions,
+            journal,
+            event_log,
+            webhook_failures,
+        })
         .layer(ServiceBuilder::new().layer(middleware::from_fn(force_local_request)));
 
     // there is option_layer() in tower but this changes the error type which mages it incompatible with servicebuilder so add it separately
@@ -147,7 +689,8 @@ async fn main() -> Result<(), Box<dyn Error>> {
         Some(allow_origin) => app.layer(
             CorsLayer::new()
                 .allow_origin(allow_origin)
-                .allow_methods([Method::GET]),
+                .allow_methods([Method::GET, Method::POST, Method::DELETE])
+                .allow_headers([header::CONTENT_TYPE]),
         ),
         None => app,
     };
@@ -159,39 +702,384 @@ async fn main() -> Result<(), Box<dyn Error>> {
     .parse::<SocketAddr>()?;
 
     tracing::info!("getraenkekassengeraete listening on {}", addr);
-    axum::Server::bind(&addr)
+    let server = axum::Server::bind(&addr)
         .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .await?;
+        .with_graceful_shutdown({
+            let cancel = cancel.clone();
+            async move { cancel.cancelled().await }
+        });
+    tokio::pin!(server);
+    tokio::select! {
+        r = &mut server => return Ok(r?),
+        r = shutdown_signal() => r?,
+    }
+
+    tracing::info!("Shutting down");
+    // the bridges forward everything up to server-shutdown before they see the cancellation
+    disconnect_clients(&events);
+    // stops accepting connections and all devices
+    cancel.cancel();
+    let finished = async {
+        if let Err(e) = server.await {
+            tracing::error!("Error shutting down server {}", e);
+        }
+        let _ = device_events.await;
+        if let Some(mqtt) = mqtt {
+            let _ = mqtt.await;
+        }
+        if let Some(webhooks) = webhooks {
+            let _ = webhooks.await;
+        }
+        if let Some(dbus) = dbus {
+            let _ = dbus.await;
+        }
+        if let Some(event_log_writer) = event_log_writer {
+            let _ = event_log_writer.await;
+        }
+    };
+    if tokio::time::timeout(shutdown_timeout, finished)
+        .await
+        .is_err()
+    {
+        tracing::warn!("Shutdown timed out after {:?}", shutdown_timeout);
+    }
 
     Ok(())
 }
 
+#[derive(Debug, Deserialize)]
+struct StreamParams {
+    /// comma separated list of event types
+    types: Option<String>,
+}
+
 async fn cashier_event_stream(
-    State(clients): State<Clients>,
+    State(events): State<EventBus>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(params): Query<StreamParams>,
 ) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
-    // Use a counter to assign a new unique ID for this client.
-    let my_id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
-
-    // Use an unbounded channel to handle buffering and flushing of messages
-    // to the event source...
-    let (tx, rx) = mpsc::unbounded_channel();
-    let rx = UnboundedReceiverStream::new(rx);
-
-    // Save the sender in our list of connected users.
-    {
-        let mut clients = clients.lock().unwrap();
-        clients.insert(my_id, tx);
-        tracing::debug!("Connected clients: {}", clients.len());
-    }
+    let info = SubscriberInfo {
+        remote_addr: Some(addr),
+        user_agent: headers
+            .get(header::USER_AGENT)
+            .and_then(|agent| agent.to_str().ok())
+            .map(str::to_string),
+        types: params
+            .types
+            .map(|types| types.split(',').map(|t| t.trim().to_string()).collect()),
+        connected_at: SystemTime::now(),
+    };
+    let mut subscriber = events.subscribe(info);
+    tracing::debug!("Client {} connected from {}", subscriber.id(), addr);
 
-    let stream = rx.map(|msg: Message| {
-        Ok(Event::default()
-            .event((msg.r#type).clone())
-            .data(serde_json::to_string(&msg.id).unwrap()))
-    });
+    let stream = stream! {
+        // the id is needed to lease devices
+        yield Ok(Event::default()
+            .event("client-connected")
+            .data(serde_json::json!({ "id": subscriber.id() }).to_string()));
+        while let Some(msg) = subscriber.recv().await {
+            yield Ok(Event::default()
+                .event(msg.r#type)
+                .data(serde_json::to_string(&msg.data).unwrap()));
+        }
+    };
     Sse::new(stream).keep_alive(
         axum::response::sse::KeepAlive::new()
             .interval(Duration::from_secs(10))
             .text(""),
     )
 }
+
+async fn client_status(State(events): State<EventBus>) -> Json<BTreeMap<u64, SubscriberStatus>> {
+    Json(events.subscribers())
+}
+
+async fn disconnect_client(
+    State(events): State<EventBus>,
+    axum::extract::Path(id): axum::extract::Path<u64>,
+) -> StatusCode {
+    if events.disconnect(id) {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::NOT_FOUND
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct LeaseRequest {
+    devices: Vec<String>,
+    duration_ms: u64,
+}
+
+async fn lease_devices(
+    State(events): State<EventBus>,
+    axum::extract::Path(id): axum::extract::Path<u64>,
+    Json(request): Json<LeaseRequest>,
+) -> Result<Json<BTreeMap<String, LeaseStatus>>, (StatusCode, String)> {
+    match events.lease(
+        id,
+        &request.devices,
+        Duration::from_millis(request.duration_ms),
+    ) {
+        Ok(_) => Ok(Json(
+            events
+                .leases()
+                .into_iter()
+                .filter(|(_, lease)| lease.holder == id)
+                .collect(),
+        )),
+        Err(e) => {
+            let status = match e {
+                LeaseError::UnknownSubscriber => StatusCode::NOT_FOUND,
+                LeaseError::Busy { .. } => StatusCode::CONFLICT,
+            };
+            Err((status, e.to_string()))
+        }
+    }
+}
+
+async fn release_devices(
+    State(events): State<EventBus>,
+    axum::extract::Path(id): axum::extract::Path<u64>,
+) -> StatusCode {
+    events.release(id, None);
+    StatusCode::NO_CONTENT
+}
+
+async fn lease_status(State(events): State<EventBus>) -> Json<BTreeMap<String, LeaseStatus>> {
+    Json(events.leases())
+}
+
+async fn session_status(
+    State(sessions): State<Option<SessionTracker>>,
+) -> Result<Json<Option<Session>>, (StatusCode, String)> {
+    match sessions {
+        Some(sessions) => Ok(Json(sessions.current())),
+        None => Err((StatusCode::NOT_FOUND, "sessions are disabled".to_string())),
+    }
+}
+
+async fn checkout(
+    State(events): State<EventBus>,
+    State(sessions): State<Option<SessionTracker>>,
+    State(journal): State<Option<Arc<Journal>>>,
+) -> Result<(StatusCode, Json<serde_json::Value>), (StatusCode, String)> {
+    let (sessions, journal) = match (sessions, journal) {
+        (Some(sessions), Some(journal)) => (sessions, journal),
+        _ => {
+            return Err((
+                StatusCode::NOT_FOUND,
+                "transaction journal is disabled".to_string(),
+            ))
+        }
+    };
+    // taken right away, so the purchase can't be checked out twice
+    let (session, update) = sessions
+        .checkout()
+        .ok_or((StatusCode::CONFLICT, "no open session".to_string()))?;
+    let transaction = Transaction::from_session(&session);
+    let key = transaction.key.clone();
+    // the journal syncs every write to disk
+    let result = tokio::task::spawn_blocking(move || {
+        journal.enqueue(transaction).map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|result| result);
+    if let Err(e) = result {
+        tracing::error!("Error writing journal {}", e);
+        // let the customer try again
+        let id = session.id;
+        if !sessions.restore(session) {
+            tracing::warn!("Dropping session {}, another one has been opened", id);
+        }
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, e));
+    }
+    publish_session_update(&events, update);
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({ "key": key })),
+    ))
+}
+
+async fn transaction_status(
+    State(journal): State<Option<Arc<Journal>>>,
+) -> Result<Json<JournalStatus>, (StatusCode, String)> {
+    match journal {
+        Some(journal) => Ok(Json(journal.status())),
+        None => Err((
+            StatusCode::NOT_FOUND,
+            "transaction journal is disabled".to_string(),
+        )),
+    }
+}
+
+fn enabled(event_log: Option<Arc<EventLog>>) -> Result<Arc<EventLog>, (StatusCode, String)> {
+    event_log.ok_or((StatusCode::NOT_FOUND, "event log is disabled".to_string()))
+}
+
+async fn query_events(
+    State(event_log): State<Option<Arc<EventLog>>>,
+    Query(filter): Query<EventFilter>,
+) -> Result<Json<Vec<LoggedEvent>>, (StatusCode, String)> {
+    let event_log = enabled(event_log)?;
+    let events =
+        tokio::task::spawn_blocking(move || event_log.query(&filter).map_err(|e| e.to_string()))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result)
+            .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(Json(events))
+}
+
+// the export is unlimited unless asked otherwise, so it is streamed line by line
+async fn export_events(
+    State(event_log): State<Option<Arc<EventLog>>>,
+    Query(filter): Query<EventFilter>,
+) -> Result<
+    (
+        [(header::HeaderName, &'static str); 2],
+        StreamBody<ReceiverStream<Result<String, std::io::Error>>>,
+    ),
+    (StatusCode, String),
+> {
+    let event_log = enabled(event_log)?;
+    let (tx, rx) = mpsc::channel(64);
+    tokio::task::spawn_blocking(move || {
+        if tx
+            .blocking_send(Ok(eventlog::CSV_HEADER.to_string()))
+            .is_err()
+        {
+            return;
+        }
+        // stops as soon as the client is gone
+        let scanned = event_log.scan(&filter, |event| {
+            tx.blocking_send(Ok(eventlog::csv_line(&event))).is_ok()
+        });
+        if let Err(e) = scanned {
+            tracing::error!("Error exporting event log {}", e);
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8"),
+            (
+                header::CONTENT_DISPOSITION,
+                "attachment; filename=\"events.csv\"",
+            ),
+        ],
+        StreamBody::new(ReceiverStream::new(rx)),
+    ))
+}
+
+async fn webhook_failures_status(
+    State(failures): State<Option<Arc<FailureStore>>>,
+) -> Result<Json<Vec<FailedDelivery>>, (StatusCode, String)> {
+    let failures = failures.ok_or((
+        StatusCode::NOT_FOUND,
+        "no webhooks are configured".to_string(),
+    ))?;
+    tokio::task::spawn_blocking(move || failures.list().map_err(|e| e.to_string()))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result)
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))
+}
+
+async fn device_status(State(devices): State<StatusBoard>) -> Json<BTreeMap<String, DeviceStatus>> {
+    Json(devices.snapshot())
+}
+
+#[derive(Debug, Deserialize)]
+struct DeviceCommand {
+    command: String,
+}
+
+async fn device_command(
+    State(commands): State<SerialCommands>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    Json(command): Json<DeviceCommand>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    match commands.send(&name, &command.command).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(e) => {
+            let status = match e {
+                CommandError::UnknownDevice => StatusCode::NOT_FOUND,
+                CommandError::InvalidCommand => StatusCode::BAD_REQUEST,
+                CommandError::NotConnected => StatusCode::SERVICE_UNAVAILABLE,
+                CommandError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+                CommandError::Failed(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            Err((status, e.to_string()))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct NfcFeedback {
+    pattern: String,
+}
+
+async fn nfc_feedback(
+    State(feedback): State<Option<Arc<FeedbackConfig>>>,
+    Json(request): Json<NfcFeedback>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let pattern = feedback
+        .as_ref()
+        .and_then(|feedback| feedback.patterns.get(&request.pattern))
+        .copied()
+        .ok_or((StatusCode::NOT_FOUND, "unknown pattern".to_string()))?;
+    tokio::task::spawn_blocking(move || {
+        nfcfeedback::play_on_all_readers(&pattern).map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())
+    .and_then(|result| result)
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use getraenkekassengeraete::eventbus::OverflowPolicy;
+
+    #[tokio::test]
+    async fn shuts_down_with_open_session() {
+        let events = EventBus::new(16, OverflowPolicy::DropOldest);
+        let mut observer = events.observe();
+        let sessions = SessionTracker::new(true);
+        let cancel = CancellationToken::new();
+        let card = NfcEvent::Card(Some(nfcservice::CardDetail::MeteUuid("uuid".to_string())));
+        let device_events = tokio::spawn(consume_device_events(
+            Processing {
+                events: events.clone(),
+                sessions: Some(sessions.clone()),
+                idle_timeout: Some(Duration::from_secs(3600)),
+                mete: None,
+                cancel: cancel.clone(),
+            },
+            // the devices are gone, the session stays open
+            tokio_stream::iter([card]),
+            tokio_stream::empty(),
+            tokio_stream::empty(),
+            tokio_stream::empty(),
+        ));
+        while sessions.current().is_none() {
+            tokio::task::yield_now().await;
+        }
+
+        cancel.cancel();
+        tokio::time::timeout(Duration::from_secs(1), device_events)
+            .await
+            .expect("device events still running")
+            .unwrap();
+        events.close();
+        while let Some((_, message)) = observer.recv().await {
+            assert_ne!(message.r#type, "session-timeout");
+        }
+    }
+}
@@ -0,0 +1,420 @@
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, StatusCode, Uri};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::cardid;
+use crate::metecache::OfflineCache;
+
+#[derive(Debug)]
+pub enum MeteError {
+    InvalidUrl(String),
+    Http(hyper::Error),
+    Status(StatusCode),
+    Json(serde_json::Error),
+    Timeout,
+}
+
+impl fmt::Display for MeteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MeteError::InvalidUrl(url) => write!(f, "invalid url {}", url),
+            MeteError::Http(e) => write!(f, "request failed: {}", e),
+            MeteError::Status(status) => write!(f, "unexpected status {}", status),
+            MeteError::Json(e) => write!(f, "invalid response: {}", e),
+            MeteError::Timeout => write!(f, "request timed out"),
+        }
+    }
+}
+
+impl std::error::Error for MeteError {}
+
+impl MeteError {
+    /// Whether trying again later might help, i.e. Mete couldn't be reached
+    pub fn is_transient(&self) -> bool {
+        match self {
+            MeteError::Http(_) | MeteError::Timeout => true,
+            MeteError::Status(status) => status.is_server_error(),
+            MeteError::InvalidUrl(_) | MeteError::Json(_) => false,
+        }
+    }
+
+    /// Whether Mete might have processed the request anyway, e.g. it timed out
+    /// after Mete got it
+    pub fn maybe_processed(&self) -> bool {
+        match self {
+            MeteError::Timeout => true,
+            MeteError::Http(e) => !e.is_connect(),
+            MeteError::InvalidUrl(_) | MeteError::Status(_) | MeteError::Json(_) => false,
+        }
+    }
+}
+
+// mete sends prices as decimal strings ("1.50"), be lenient and accept numbers as well
+fn price<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Price {
+        Number(f64),
+        String(String),
+    }
+    match Price::deserialize(deserializer)? {
+        Price::Number(price) => Ok(price),
+        Price::String(price) => price.parse().map_err(serde::de::Error::custom),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Drink {
+    pub id: u64,
+    pub name: String,
+    #[serde(deserialize_with = "price")]
+    pub price: f64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct User {
+    pub id: u64,
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Barcode {
+    #[serde(default)]
+    id: String,
+    drink: u64,
+}
+
+/// Where a lookup result came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    Upstream,
+    /// the offline cache, Mete could not be reached
+    Cache,
+}
+
+#[derive(Debug, Clone)]
+pub struct Lookup<T> {
+    pub value: Option<T>,
+    pub source: Source,
+}
+
+// ids end up in the url path, anything else can't be a valid id anyway
+fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Client for the JSON API of our Mete server.
+///
+/// Responses (including "not found") are cached for `ttl`, so scanning the same
+/// bottle twice doesn't hit the server twice. Only plain http is supported.
+///
+/// With an offline cache every successful lookup is persisted, and used whenever
+/// Mete can't be reached.
+pub struct MeteClient {
+    base: String,
+    client: Client<HttpConnector>,
+    timeout: Duration,
+    ttl: Duration,
+    cache: Mutex<HashMap<String, (Instant, Option<serde_json::Value>)>>,
+    offline: Option<OfflineCache>,
+}
+
+impl MeteClient {
+    pub fn new(base: &str, timeout: Duration, ttl: Duration) -> Result<MeteClient, MeteError> {
+        let base = base.trim_end_matches('/').to_string();
+        if base.parse::<Uri>().is_err() {
+            return Err(MeteError::InvalidUrl(base));
+        }
+        Ok(MeteClient {
+            base,
+            client: Client::new(),
+            timeout,
+            ttl,
+            cache: Mutex::new(HashMap::new()),
+            offline: None,
+        })
+    }
+
+    pub fn with_offline_cache(mut self, offline: OfflineCache) -> MeteClient {
+        self.offline = Some(offline);
+        self
+    }
+
+    /// The drink registered for `barcode`
+    pub async fn drink(&self, barcode: &str) -> Result<Lookup<Drink>, MeteError> {
+        match self.fetch_drink(barcode).await {
+            Ok(drink) => {
+                if let (Some(offline), Some(drink)) = (&self.offline, &drink) {
+                    offline.insert_drink(barcode, drink).await;
+                }
+                Ok(Lookup {
+                    value: drink,
+                    source: Source::Upstream,
+                })
+            }
+            Err(e) => self.fall_back(e, |offline| offline.drink(barcode)),
+        }
+    }
+
+    /// The user a card belongs to. `card` is either the UUID sent by the KalkGetränk
+    /// app or the hex UID of any other card.
+    pub async fn user(&self, card: &str) -> Result<Lookup<User>, MeteError> {
+        match self.fetch_user(card).await {
+            Ok(user) => {
+                if let (Some(offline), Some(user)) = (&self.offline, &user) {
+                    offline.insert_user(card, user).await;
+                }
+                Ok(Lookup {
+                    value: user,
+                    source: Source::Upstream,
+                })
+            }
+            Err(e) => self.fall_back(e, |offline| offline.user(card)),
+        }
+    }
+
+    fn fall_back<T>(
+        &self,
+        error: MeteError,
+        lookup: impl FnOnce(&OfflineCache) -> Option<T>,
+    ) -> Result<Lookup<T>, MeteError> {
+        match self.offline.as_ref().and_then(lookup) {
+            Some(value) => {
+                tracing::warn!("Mete unavailable, using offline cache: {}", error);
+                Ok(Lookup {
+                    value: Some(value),
+                    source: Source::Cache,
+                })
+            }
+            None => Err(error),
+        }
+    }
+
+    /// Refresh the drinks of the offline cache from the full barcode and drink lists.
+    /// Returns the number of known barcodes.
+    pub async fn sync(&self) -> Result<usize, MeteError> {
+        let offline = match &self.offline {
+            Some(offline) => offline,
+            None => return Ok(0),
+        };
+        let barcodes: Vec<Barcode> = self.fetch_list("/api/v1/barcodes.json").await?;
+        let drinks: Vec<Drink> = self.fetch_list("/api/v1/drinks.json").await?;
+        let drinks: HashMap<u64, Drink> = drinks.into_iter().map(|d| (d.id, d)).collect();
+        let drinks: HashMap<String, Drink> = barcodes
+            .into_iter()
+            .filter_map(|barcode| Some((barcode.id, drinks.get(&barcode.drink)?.clone())))
+            .collect();
+        let count = drinks.len();
+        offline.replace_drinks(drinks).await;
+        Ok(count)
+    }
+
+    /// Book the drink with `barcode` on the account of `user`
+    pub async fn buy(&self, user: u64, barcode: &str) -> Result<(), MeteError> {
+        if !is_valid_id(barcode) {
+            return Err(MeteError::Status(StatusCode::BAD_REQUEST));
+        }
+        let url = format!(
+            "{}/api/v1/users/{}/buy_barcode.json?barcode={}",
+            self.base, user, barcode
+        );
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(url.parse::<Uri>().map_err(|_| MeteError::InvalidUrl(url))?)
+            .body(Body::empty())
+            .expect("request is valid");
+        let response = tokio::time::timeout(self.timeout, self.client.request(request))
+            .await
+            .map_err(|_| MeteError::Timeout)?
+            .map_err(MeteError::Http)?;
+        if !response.status().is_success() {
+            return Err(MeteError::Status(response.status()));
+        }
+        Ok(())
+    }
+
+    async fn fetch_list<T: DeserializeOwned>(&self, path: &str) -> Result<Vec<T>, MeteError> {
+        let value = tokio::time::timeout(self.timeout, self.fetch(path))
+            .await
+            .map_err(|_| MeteError::Timeout)??
+            .ok_or(MeteError::Status(StatusCode::NOT_FOUND))?;
+        serde_json::from_value(value).map_err(MeteError::Json)
+    }
+
+    async fn fetch_drink(&self, barcode: &str) -> Result<Option<Drink>, MeteError> {
+        if !is_valid_id(barcode) {
+            return Ok(None);
+        }
+        let barcode: Option<Barcode> = self
+            .get(&format!("/api/v1/barcodes/{}.json", barcode))
+            .await?;
+        match barcode {
+            Some(barcode) => {
+                self.get(&format!("/api/v1/drinks/{}.json", barcode.drink))
+                    .await
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn fetch_user(&self, card: &str) -> Result<Option<User>, MeteError> {
+        if !is_valid_id(card) {
+            return Ok(None);
+        }
+        self.get(&format!("/api/v1/users/{}.json", card)).await
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<Option<T>, MeteError> {
+        let value = match self.cached(path) {
+            Some(value) => value,
+            None => {
+                let value = tokio::time::timeout(self.timeout, self.fetch(path))
+                    .await
+                    .map_err(|_| MeteError::Timeout)??;
+                self.cache
+                    .lock()
+                    .unwrap()
+                    .insert(path.to_string(), (Instant::now(), value.clone()));
+                value
+            }
+        };
+        value
+            .map(serde_json::from_value)
+            .transpose()
+            .map_err(MeteError::Json)
+    }
+
+    fn cached(&self, path: &str) -> Option<Option<serde_json::Value>> {
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(path) {
+            Some((fetched_at, value)) if fetched_at.elapsed() < self.ttl => Some(value.clone()),
+            Some(_) => {
+                cache.remove(path);
+                None
+            }
+            None => None,
+        }
+    }
+
+    async fn fetch(&self, path: &str) -> Result<Option<serde_json::Value>, MeteError> {
+        let url = format!("{}{}", self.base, path);
+        let uri = url.parse::<Uri>().map_err(|_| MeteError::InvalidUrl(url))?;
+        if cardid::logs_redacted() && path.starts_with("/api/v1/users/") {
+            tracing::debug!("Fetching user");
+        } else {
+            tracing::debug!("Fetching {}", uri);
+        }
+        let response = self.client.get(uri).await.map_err(MeteError::Http)?;
+        match response.status() {
+            StatusCode::OK => {}
+            StatusCode::NOT_FOUND => return Ok(None),
+            status => return Err(MeteError::Status(status)),
+        }
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(MeteError::Http)?;
+        Ok(Some(
+            serde_json::from_slice(&body).map_err(MeteError::Json)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metecache::OfflineCache;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use std::convert::Infallible;
+
+    async fn mete(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+        let body = match request.uri().path() {
+            "/api/v1/barcodes/4001.json" => r#"{"id": "4001", "drink": 3}"#,
+            "/api/v1/drinks/3.json" => r#"{"id": 3, "name": "Club Mate", "price": "1.50"}"#,
+            "/api/v1/users/card.json" => r#"{"id": 7, "name": "alice"}"#,
+            _ => {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                return Ok(response);
+            }
+        };
+        Ok(Response::new(Body::from(body)))
+    }
+
+    fn serve() -> String {
+        let make = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(mete)) });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+        let base = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        base
+    }
+
+    fn client(base: &str) -> MeteClient {
+        MeteClient::new(base, Duration::from_secs(1), Duration::ZERO).unwrap()
+    }
+
+    fn club_mate() -> Drink {
+        Drink {
+            id: 3,
+            name: "Club Mate".to_string(),
+            price: 1.5,
+        }
+    }
+
+    fn alice() -> User {
+        User {
+            id: 7,
+            name: "alice".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn lookups() {
+        let mete = client(&serve());
+
+        let drink = mete.drink("4001").await.unwrap();
+        assert_eq!(drink.value, Some(club_mate()));
+        assert_eq!(drink.source, Source::Upstream);
+        let user = mete.user("card").await.unwrap();
+        assert_eq!(user.value, Some(alice()));
+
+        assert_eq!(mete.drink("9999").await.unwrap().value, None);
+        assert_eq!(mete.user("nobody").await.unwrap().value, None);
+        // never sent to Mete
+        assert_eq!(mete.drink("../users/card").await.unwrap().value, None);
+    }
+
+    #[tokio::test]
+    async fn offline_cache_fallback() {
+        let path = std::env::temp_dir().join(format!("mete-test-{}.json", std::process::id()));
+        let online = client(&serve()).with_offline_cache(OfflineCache::load(&path).unwrap());
+        online.drink("4001").await.unwrap();
+        online.user("card").await.unwrap();
+
+        // nothing listens on a port we just had
+        let base = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}", listener.local_addr().unwrap())
+        };
+        let offline = client(&base).with_offline_cache(OfflineCache::load(&path).unwrap());
+        let drink = offline.drink("4001").await.unwrap();
+        assert_eq!(drink.value, Some(club_mate()));
+        assert_eq!(drink.source, Source::Cache);
+        let user = offline.user("card").await.unwrap();
+        assert_eq!(user.value, Some(alice()));
+        assert_eq!(user.source, Source::Cache);
+        let unknown = offline.drink("5000").await.unwrap_err();
+        assert!(unknown.is_transient(), "{}", unknown);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
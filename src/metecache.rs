@@ -0,0 +1,161 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::mete::{Drink, User};
+use crate::unixtime;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheData {
+    // barcode -> drink
+    #[serde(default)]
+    drinks: HashMap<String, Drink>,
+    // card uuid or uid -> user
+    #[serde(default)]
+    users: HashMap<String, User>,
+    #[serde(default)]
+    synced_at_ms: Option<u64>,
+    // counts the changes, so an older copy can't overwrite a newer one on disk
+    #[serde(skip)]
+    changes: u64,
+}
+
+impl CacheData {
+    fn snapshot(&mut self) -> (Vec<u8>, u64) {
+        self.changes += 1;
+        (serde_json::to_vec(self).unwrap(), self.changes)
+    }
+}
+
+/// Copy of the Mete data we need to resolve events while the network is down,
+/// persisted as JSON file.
+///
+/// Barcodes are synced from the full drink list, users are only known once they
+/// were looked up successfully as Mete can't list them by card.
+pub struct OfflineCache {
+    path: PathBuf,
+    data: Mutex<CacheData>,
+    // the change last written to disk
+    written: Arc<Mutex<u64>>,
+}
+
+impl OfflineCache {
+    /// Reads the cache from `path`. A missing file results in an empty cache.
+    pub fn load(path: &Path) -> Result<OfflineCache, Box<dyn Error>> {
+        let data = match std::fs::File::open(path) {
+            Ok(file) => serde_json::from_reader(std::io::BufReader::new(file))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => CacheData::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(OfflineCache {
+            path: path.to_path_buf(),
+            data: Mutex::new(data),
+            written: Arc::default(),
+        })
+    }
+
+    pub fn drink(&self, barcode: &str) -> Option<Drink> {
+        self.data.lock().unwrap().drinks.get(barcode).cloned()
+    }
+
+    pub fn user(&self, card: &str) -> Option<User> {
+        self.data.lock().unwrap().users.get(card).cloned()
+    }
+
+    pub async fn insert_drink(&self, barcode: &str, drink: &Drink) {
+        let snapshot = {
+            let mut data = self.data.lock().unwrap();
+            if data.drinks.get(barcode) == Some(drink) {
+                return;
+            }
+            data.drinks.insert(barcode.to_string(), drink.clone());
+            data.snapshot()
+        };
+        self.save(snapshot).await;
+    }
+
+    pub async fn insert_user(&self, card: &str, user: &User) {
+        let snapshot = {
+            let mut data = self.data.lock().unwrap();
+            if data.users.get(card) == Some(user) {
+                return;
+            }
+            data.users.insert(card.to_string(), user.clone());
+            data.snapshot()
+        };
+        self.save(snapshot).await;
+    }
+
+    /// Replace all drinks with a fresh export
+    pub async fn replace_drinks(&self, drinks: HashMap<String, Drink>) {
+        let snapshot = {
+            let mut data = self.data.lock().unwrap();
+            data.drinks = drinks;
+            data.synced_at_ms = Some(unixtime::now_millis());
+            data.snapshot()
+        };
+        self.save(snapshot).await;
+    }
+
+    async fn save(&self, (json, change): (Vec<u8>, u64)) {
+        let path = self.path.clone();
+        let written = self.written.clone();
+        let result = tokio::task::spawn_blocking(move || -> std::io::Result<()> {
+            let mut written = written.lock().unwrap();
+            if *written > change {
+                return Ok(());
+            }
+            // write to a temporary file first so a crash can't leave half a cache behind
+            let tmp = path.with_extension("tmp");
+            std::fs::write(&tmp, json)?;
+            std::fs::rename(&tmp, &path)?;
+            *written = change;
+            Ok(())
+        })
+        .await
+        .map_err(Box::<dyn Error + Send + Sync>::from)
+        .and_then(|result| Ok(result?));
+        if let Err(e) = result {
+            tracing::error!("Error saving offline cache {:?} {}", self.path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn saved_cache_round_trips() {
+        let path = std::env::temp_dir().join(format!("metecache-test-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let drink = Drink {
+            id: 1,
+            name: "Club Mate".to_string(),
+            price: 1.5,
+        };
+        let user = User {
+            id: 7,
+            name: "alice".to_string(),
+        };
+
+        let cache = OfflineCache::load(&path).unwrap();
+        cache
+            .replace_drinks(HashMap::from([("4001".to_string(), drink.clone())]))
+            .await;
+        cache.insert_user("card", &user).await;
+        let synced_at_ms = cache.data.lock().unwrap().synced_at_ms;
+
+        let loaded = OfflineCache::load(&path).unwrap();
+        assert_eq!(loaded.drink("4001"), Some(drink));
+        assert_eq!(loaded.drink("5000"), None);
+        assert_eq!(loaded.user("card"), Some(user));
+        assert!(synced_at_ms.is_some());
+        assert_eq!(loaded.data.lock().unwrap().synced_at_ms, synced_at_ms);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
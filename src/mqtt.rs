@@ -0,0 +1,307 @@
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+use crate::eventbus::EventBus;
+use crate::supervisor::{Backoff, DeviceStatus, StatusBoard};
+
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub credentials: Option<(String, String)>,
+    /// topic of events, `{device}` and `{type}` are replaced
+    pub topic: String,
+    /// `online`/`offline` is published here, the status of each device below
+    pub status_topic: String,
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+impl MqttConfig {
+    pub fn new(host: impl Into<String>, port: u16) -> MqttConfig {
+        MqttConfig {
+            host: host.into(),
+            port,
+            client_id: "getraenkekassengeraete".to_string(),
+            credentials: None,
+            topic: "kasse/{device}/{type}".to_string(),
+            status_topic: "kasse/status".to_string(),
+            qos: QoS::AtMostOnce,
+            retain: false,
+        }
+    }
+
+    fn event_topic(&self, device: Option<&str>, r#type: &str) -> String {
+        self.topic
+            // events of the server itself, e.g. session updates
+            .replace("{device}", &topic_level(device.unwrap_or("server")))
+            .replace("{type}", &topic_level(r#type))
+    }
+
+    fn device_status_topic(&self, device: &str) -> String {
+        format!("{}/{}", self.status_topic, topic_level(device))
+    }
+}
+
+// names come from the configuration, a `/` would add a level and `+`/`#` are
+// wildcards that can't be published to
+fn topic_level(name: &str) -> String {
+    name.replace(['/', '+', '#'], "_")
+}
+
+pub fn parse_qos(qos: &str) -> Result<QoS, String> {
+    match qos {
+        "0" => Ok(QoS::AtMostOnce),
+        "1" => Ok(QoS::AtLeastOnce),
+        "2" => Ok(QoS::ExactlyOnce),
+        _ => Err(format!("invalid qos {:?}, expected 0, 1 or 2", qos)),
+    }
+}
+
+// retained, the status of every device that changed since it was last `published`
+fn publish_status(
+    client: &AsyncClient,
+    config: &MqttConfig,
+    devices: &StatusBoard,
+    published: &mut BTreeMap<String, DeviceStatus>,
+    resend: bool,
+) {
+    let snapshot = devices.snapshot();
+    // an empty retained message removes the status of devices that are gone
+    let gone: Vec<String> = published
+        .keys()
+        .filter(|device| !snapshot.contains_key(*device))
+        .cloned()
+        .collect();
+    for device in gone {
+        let topic = config.device_status_topic(&device);
+        match client.try_publish(topic, QoS::AtLeastOnce, true, Vec::new()) {
+            Ok(()) => {
+                published.remove(&device);
+            }
+            Err(e) => tracing::warn!("mqtt: error clearing status {}", e),
+        }
+    }
+    for (device, status) in snapshot {
+        if !resend && published.get(&device) == Some(&status) {
+            continue;
+        }
+        let topic = config.device_status_topic(&device);
+        let payload = serde_json::to_vec(&status).unwrap();
+        match client.try_publish(topic, QoS::AtLeastOnce, true, payload) {
+            Ok(()) => {
+                published.insert(device, status);
+            }
+            Err(e) => tracing::warn!("mqtt: error publishing status {}", e),
+        }
+    }
+}
+
+/// Publishes all events and device status changes to an MQTT broker until `cancel`
+/// fires. The connection is reestablished whenever it gets lost, events published
+/// in the meantime are dropped.
+pub async fn run(
+    config: MqttConfig,
+    events: EventBus,
+    devices: StatusBoard,
+    cancel: CancellationToken,
+) {
+    let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(
+        &config.status_topic,
+        "offline",
+        QoS::AtLeastOnce,
+        true,
+    ));
+    if let Some((username, password)) = &config.credentials {
+        options.set_credentials(username, password);
+    }
+    let (client, mut eventloop) = AsyncClient::new(options, 64);
+    let mut observer = events.observe();
+    let mut status = devices.watch();
+    let mut published: BTreeMap<String, DeviceStatus> = BTreeMap::new();
+    let mut connected = false;
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+
+    loop {
+        tokio::select! {
+            // drain the events before noticing the shutdown, so server-shutdown goes out
+            biased;
+            message = observer.recv() => {
+                let (device, message) = match message {
+                    Some(message) => message,
+                    None => break,
+                };
+                if !connected {
+                    continue;
+                }
+                let topic = config.event_topic(device.as_deref(), &message.r#type);
+                let payload = serde_json::to_vec(&message.data).unwrap();
+                // the eventloop is driven by this very loop, waiting for queue space would deadlock
+                if let Err(e) = client.try_publish(topic, config.qos, config.retain, payload) {
+                    tracing::warn!("mqtt: dropping {} event {}", message.r#type, e);
+                }
+            },
+            event = eventloop.poll() => match event {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    tracing::info!("mqtt: connected to {}:{}", config.host, config.port);
+                    connected = true;
+                    backoff.reset();
+                    let online = client.try_publish(&config.status_topic, QoS::AtLeastOnce, true, "online");
+                    if let Err(e) = online {
+                        tracing::warn!("mqtt: error publishing status {}", e);
+                    }
+                    // retained messages might be gone, send all of them again
+                    publish_status(&client, &config, &devices, &mut published, true);
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    connected = false;
+                    let delay = backoff.next_delay();
+                    tracing::warn!("mqtt: connection failed, retrying in {:?}: {}", delay, e);
+                    tokio::select! {
+                        _ = tokio::time::sleep(delay) => {},
+                        _ = cancel.cancelled() => break,
+                    }
+                }
+            },
+            _ = status.changed(), if connected => {
+                publish_status(&client, &config, &devices, &mut published, false);
+            },
+            _ = cancel.cancelled() => break,
+        }
+    }
+
+    if !connected {
+        return;
+    }
+    // a clean disconnect suppresses the last will, so say goodbye ourselves
+    let goodbye = client
+        .try_publish(&config.status_topic, QoS::AtLeastOnce, true, "offline")
+        .and_then(|_| client.try_disconnect());
+    if let Err(e) = goodbye {
+        tracing::warn!("mqtt: error disconnecting {}", e);
+        return;
+    }
+    let flush = async {
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Outgoing(rumqttc::Outgoing::Disconnect)) | Err(_) => return,
+                Ok(_) => {}
+            }
+        }
+    };
+    if tokio::time::timeout(Duration::from_secs(1), flush)
+        .await
+        .is_err()
+    {
+        tracing::warn!("mqtt: timeout disconnecting");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eventbus::{Message, OverflowPolicy};
+    use crate::supervisor::Supervisor;
+    use std::collections::HashMap;
+
+    #[test]
+    fn names_stay_one_topic_level() {
+        let config = MqttConfig::new("localhost", 1883);
+        assert_eq!(
+            config.event_topic(Some("tür/1"), "a+b#"),
+            "kasse/tür_1/a_b_"
+        );
+        assert_eq!(config.event_topic(None, "barcode"), "kasse/server/barcode");
+        assert_eq!(config.device_status_topic("#"), "kasse/status/_");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs an MQTT broker, set MQTT_TEST_HOST"]
+    async fn publishes_to_broker() {
+        let host = std::env::var("MQTT_TEST_HOST").unwrap_or_else(|_| "localhost".to_string());
+        let prefix = format!("kasse-test-{}", std::process::id());
+        let mut config = MqttConfig::new(&host, 1883);
+        config.client_id = format!("{}-server", prefix);
+        config.topic = format!("{}/{{device}}/{{type}}", prefix);
+        config.status_topic = format!("{}/status", prefix);
+
+        let (subscriber, mut subscription) =
+            AsyncClient::new(MqttOptions::new(format!("{}-sub", prefix), &host, 1883), 16);
+        subscriber
+            .subscribe(format!("{}/#", prefix), QoS::AtLeastOnce)
+            .await
+            .unwrap();
+        loop {
+            if let Event::Incoming(Packet::SubAck(_)) = subscription.poll().await.unwrap() {
+                break;
+            }
+        }
+
+        let events = EventBus::new(16, OverflowPolicy::DropOldest);
+        let devices = StatusBoard::default();
+        let cancel = CancellationToken::new();
+        devices.register(&Supervisor::new(
+            "scanner",
+            Backoff::new(Duration::from_secs(1), Duration::from_secs(1)),
+            cancel.clone(),
+        ));
+        let bridge = tokio::spawn(run(config, events.clone(), devices.clone(), cancel.clone()));
+
+        let event_topic = format!("{}/server/barcode", prefix);
+        let mut received = HashMap::new();
+        let receive = async {
+            while !received.contains_key(&event_topic)
+                || !received.contains_key(&format!("{}/status/scanner", prefix))
+            {
+                let publish = match subscription.poll().await.unwrap() {
+                    Event::Incoming(Packet::Publish(publish)) => publish,
+                    _ => continue,
+                };
+                let payload = String::from_utf8(publish.payload.to_vec()).unwrap();
+                // the bridge is connected once it says so
+                if publish.topic == format!("{}/status", prefix) && payload == "online" {
+                    events.publish(Message {
+                        r#type: "barcode".to_string(),
+                        data: "4029764001807".into(),
+                    });
+                }
+                received.insert(publish.topic, payload);
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), receive)
+            .await
+            .expect("timeout waiting for the bridge");
+
+        assert_eq!(received[&format!("{}/status", prefix)], "online");
+        assert_eq!(
+            received[&format!("{}/status/scanner", prefix)],
+            r#"{"state":"connecting"}"#
+        );
+        assert_eq!(received[&event_topic], r#""4029764001807""#);
+
+        // a device that is gone loses its retained status
+        devices.unregister("scanner");
+        let cleared = async {
+            loop {
+                if let Event::Incoming(Packet::Publish(publish)) =
+                    subscription.poll().await.unwrap()
+                {
+                    if publish.topic == format!("{}/status/scanner", prefix) {
+                        return publish.payload;
+                    }
+                }
+            }
+        };
+        let payload = tokio::time::timeout(Duration::from_secs(10), cleared)
+            .await
+            .expect("timeout waiting for the cleared status");
+        assert!(payload.is_empty());
+        cancel.cancel();
+        bridge.await.unwrap();
+    }
+}
@@ -0,0 +1,218 @@
+use pcsc::*;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::path::Path;
+
+// IOCTL_CCID_ESCAPE of the pcsc-lite ccid driver. Used to talk to the reader itself
+// while there is no card on it
+const IOCTL_CCID_ESCAPE: u32 = 3500;
+
+/// "Set Buzzer Output during Card Detection" off
+pub const DISABLE_CARD_BEEP: [u8; 5] = [0xFF, 0x00, 0x52, 0x00, 0x00];
+
+fn default_on_ms() -> u32 {
+    200
+}
+
+fn default_repetitions() -> u8 {
+    1
+}
+
+/// LED and buzzer pattern of ACR122U style readers
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct FeedbackPattern {
+    #[serde(default)]
+    pub red: bool,
+    #[serde(default)]
+    pub green: bool,
+    /// how long the LEDs (and the buzzer) are on per repetition
+    #[serde(default = "default_on_ms")]
+    pub on_ms: u32,
+    /// pause between repetitions
+    #[serde(default)]
+    pub off_ms: u32,
+    #[serde(default = "default_repetitions")]
+    pub repetitions: u8,
+    #[serde(default)]
+    pub buzzer: bool,
+}
+
+impl FeedbackPattern {
+    /// The "Bi-Color LED and Buzzer Control" pseudo APDU. Only the blinking state is
+    /// touched, so the LEDs return to whatever the reader shows when idle.
+    pub fn apdu(&self) -> [u8; 9] {
+        let mut p2 = 0u8;
+        if self.red {
+            // blink red, starting with red on
+            p2 |= 0x40 | 0x10;
+        }
+        if self.green {
+            p2 |= 0x80 | 0x20;
+        }
+        // durations are in units of 100ms
+        let t1 = (self.on_ms / 100).clamp(1, 255) as u8;
+        let t2 = (self.off_ms / 100).min(255) as u8;
+        let buzzer = if self.buzzer { 0x01 } else { 0x00 };
+        [0xFF, 0x00, 0x40, p2, 0x04, t1, t2, self.repetitions, buzzer]
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FeedbackConfig {
+    pub patterns: HashMap<String, FeedbackPattern>,
+    /// pattern played after reading a card with the KalkGetränk app
+    pub on_uuid: Option<String>,
+    /// pattern played after reading any other card
+    pub on_plain: Option<String>,
+    /// pattern played if a card could not be read
+    pub on_invalid: Option<String>,
+    /// turn off the beep the reader itself emits whenever it detects a card
+    pub disable_card_beep: bool,
+}
+
+impl Default for FeedbackConfig {
+    fn default() -> FeedbackConfig {
+        let mut patterns = HashMap::new();
+        patterns.insert(
+            "success".to_string(),
+            FeedbackPattern {
+                red: false,
+                green: true,
+                on_ms: 500,
+                off_ms: 0,
+                repetitions: 1,
+                buzzer: false,
+            },
+        );
+        patterns.insert(
+            "error".to_string(),
+            FeedbackPattern {
+                red: true,
+                green: false,
+                on_ms: 200,
+                off_ms: 200,
+                repetitions: 3,
+                buzzer: true,
+            },
+        );
+        FeedbackConfig {
+            patterns,
+            on_uuid: Some("success".to_string()),
+            on_plain: None,
+            on_invalid: Some("error".to_string()),
+            disable_card_beep: true,
+        }
+    }
+}
+
+impl FeedbackConfig {
+    /// Reads patterns from a JSON file. Patterns not defined in the file keep their defaults.
+    pub fn load(path: &Path) -> Result<FeedbackConfig, Box<dyn StdError>> {
+        let file = std::fs::File::open(path)?;
+        let mut config: FeedbackConfig = serde_json::from_reader(file)?;
+        for (name, pattern) in FeedbackConfig::default().patterns {
+            config.patterns.entry(name).or_insert(pattern);
+        }
+        Ok(config)
+    }
+
+    pub fn pattern(&self, name: &Option<String>) -> Option<&FeedbackPattern> {
+        name.as_ref().and_then(|name| self.patterns.get(name))
+    }
+}
+
+fn check_response(response: &[u8]) -> Result<(), Box<dyn StdError>> {
+    let l = response.len();
+    if l < 2 || response[l - 2] != 0x90 {
+        return Err(format!("Reader rejected feedback command: {:x?}", response).into());
+    }
+    Ok(())
+}
+
+/// Send a pseudo APDU via a card currently on the reader
+pub fn send_via_card(card: &Card, apdu: &[u8]) -> Result<(), Box<dyn StdError>> {
+    tracing::debug!("Sending pseudo APDU: {:x?}", apdu);
+    let mut rapdu_buf = [0; MAX_BUFFER_SIZE];
+    let rapdu = card.transmit(apdu, &mut rapdu_buf)?;
+    tracing::debug!("Pseudo APDU response: {:x?}", rapdu);
+    check_response(rapdu)
+}
+
+/// Send a pseudo APDU to the reader itself, no card required
+pub fn send_direct(
+    ctx: &Context,
+    reader: &std::ffi::CStr,
+    apdu: &[u8],
+) -> Result<(), Box<dyn StdError>> {
+    let card = ctx.connect(reader, ShareMode::Direct, Protocols::UNDEFINED)?;
+    tracing::debug!("Sending escape command: {:x?}", apdu);
+    let mut rapdu_buf = [0; MAX_BUFFER_SIZE];
+    let rapdu = card.control(ctl_code(IOCTL_CCID_ESCAPE.into()), apdu, &mut rapdu_buf)?;
+    tracing::debug!("Escape command response: {:x?}", rapdu);
+    check_response(rapdu)
+}
+
+/// Play `pattern` on all connected readers
+pub fn play_on_all_readers(pattern: &FeedbackPattern) -> Result<(), Box<dyn StdError>> {
+    let ctx = Context::establish(Scope::User)?;
+    let mut readers_buf = [0; 2048];
+    let apdu = pattern.apdu();
+    for reader in ctx.list_readers(&mut readers_buf)? {
+        // the reader only accepts escape commands in direct mode while there is no card
+        let result = match ctx.connect(reader, ShareMode::Shared, Protocols::ANY) {
+            Ok(card) => send_via_card(&card, &apdu),
+            Err(Error::NoSmartcard) => send_direct(&ctx, reader, &apdu),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            tracing::error!("Error playing feedback on {:?} {}", reader, e);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apdu_bytes() {
+        let config = FeedbackConfig::default();
+        assert_eq!(
+            config.patterns["success"].apdu(),
+            [0xFF, 0x00, 0x40, 0xA0, 0x04, 0x05, 0x00, 0x01, 0x00]
+        );
+        assert_eq!(
+            config.patterns["error"].apdu(),
+            [0xFF, 0x00, 0x40, 0x50, 0x04, 0x02, 0x02, 0x03, 0x01]
+        );
+
+        // durations are clamped to what fits into a byte, the LEDs are on at least 100ms
+        let both = FeedbackPattern {
+            red: true,
+            green: true,
+            on_ms: 0,
+            off_ms: 60_000,
+            repetitions: 2,
+            buzzer: false,
+        };
+        assert_eq!(
+            both.apdu(),
+            [0xFF, 0x00, 0x40, 0xF0, 0x04, 0x01, 0xFF, 0x02, 0x00]
+        );
+        let neither = FeedbackPattern {
+            red: false,
+            green: false,
+            on_ms: 60_000,
+            off_ms: 150,
+            repetitions: 1,
+            buzzer: true,
+        };
+        assert_eq!(
+            neither.apdu(),
+            [0xFF, 0x00, 0x40, 0x00, 0x04, 0xFF, 0x01, 0x01, 0x01]
+        );
+    }
+}
@@ -2,14 +2,33 @@ use async_stream::stream;
 use futures::Stream;
 use pcsc::*;
 use std::error::Error as StdError;
+use std::fmt;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::cardid::{self, CardIdHasher, Redacted};
+use crate::nfcfeedback::{self, FeedbackConfig};
+use crate::supervisor::Supervisor;
+
+// upper bound for a single wait on the readers. a cancelled context wakes us up
+// right away, this is just a safety net
+const STATUS_CHANGE_TIMEOUT: Duration = Duration::from_secs(2);
 
 fn is_dead(rs: &ReaderState) -> bool {
     rs.event_state().intersects(State::UNKNOWN | State::IGNORE)
 }
 
+// responses contain the UUID or UID of the card
+fn log_response(rapdu: &[u8]) {
+    if cardid::logs_redacted() {
+        tracing::debug!("APDU response: {} bytes", rapdu.len());
+    } else {
+        tracing::debug!("APDU response: {:x?}", rapdu);
+    }
+}
+
 #[derive(Debug)]
 enum MeteCardState {
     UnsupportedApplicationSelect,
@@ -24,7 +43,7 @@ fn get_mete_card_state(card: &Card) -> Result<MeteCardState, Box<dyn StdError>>
     tracing::debug!("Sending APDU: {:x?}", apdu);
     let mut rapdu_buf = [0; MAX_BUFFER_SIZE];
     let rapdu = card.transmit(apdu, &mut rapdu_buf)?;
-    tracing::debug!("APDU response: {:x?}", rapdu);
+    log_response(rapdu);
     let l = rapdu.len();
     if l == 0 {
         return Ok(MeteCardState::UnsupportedApplicationSelect);
@@ -36,7 +55,7 @@ fn get_mete_card_state(card: &Card) -> Result<MeteCardState, Box<dyn StdError>>
     let apdu = b"\xd0\x00\x00\x00\x24";
     tracing::debug!("Sending APDU: {:x?}", apdu);
     let rapdu = card.transmit(apdu, &mut rapdu_buf)?;
-    tracing::debug!("APDU response: {:x?}", rapdu);
+    log_response(rapdu);
     let l = rapdu.len();
     if l == 0 {
         return Ok(MeteCardState::UnsupportedApplicationSelect);
@@ -54,7 +73,7 @@ fn get_uid(card: &Card) -> Result<Option<Vec<u8>>, Box<dyn StdError>> {
     tracing::debug!("Sending APDU: {:x?}", apdu);
     let mut rapdu_buf = [0; MAX_BUFFER_SIZE];
     let rapdu = card.transmit(apdu, &mut rapdu_buf)?;
-    tracing::debug!("APDU response: {:x?}", rapdu);
+    log_response(rapdu);
     let l = rapdu.len();
     // min length 3: at least one u8 as id + successful response
     if l < 3 || rapdu[l - 2] != 0x90 || rapdu[l - 1] != 0x00 {
@@ -64,85 +83,137 @@ fn get_uid(card: &Card) -> Result<Option<Vec<u8>>, Box<dyn StdError>> {
     }
 }
 
-fn parse_card(card: Card) -> Result<Option<CardDetail>, Box<dyn StdError>> {
-    let result = match get_mete_card_state(&card)? {
+fn parse_card(card: &Card) -> Result<Option<CardDetail>, Box<dyn StdError>> {
+    let result = match get_mete_card_state(card)? {
         // YES! KalkGetränke app <3
         MeteCardState::Uuid(uuid) => Some(CardDetail::MeteUuid(uuid)),
         MeteCardState::ApplicationUnknown => None,
         MeteCardState::InvalidAnswer => None,
-        MeteCardState::UnsupportedApplicationSelect => {
-            get_uid(&card)?.map(|uid| CardDetail::Plain(uid))
-        }
+        MeteCardState::UnsupportedApplicationSelect => get_uid(card)?.map(CardDetail::Plain),
     };
     Ok(result)
 }
 
-#[derive(Debug)]
 pub enum CardDetail {
     MeteUuid(String),
+    /// the UID, or its HMAC if card ids are hashed
     Plain(Vec<u8>),
 }
 
-#[derive(Default)]
+impl fmt::Debug for CardDetail {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CardDetail::MeteUuid(uuid) => f.debug_tuple("MeteUuid").field(&Redacted(uuid)).finish(),
+            CardDetail::Plain(uid) if cardid::logs_redacted() => f
+                .debug_tuple("Plain")
+                .field(&format_args!("<{} bytes>", uid.len()))
+                .finish(),
+            CardDetail::Plain(uid) => f.debug_tuple("Plain").field(uid).finish(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum NfcEvent {
+    /// A card was presented. `None` if it could not be read
+    Card(Option<CardDetail>),
+    /// The card was taken off the reader again
+    Removed,
+}
+
+fn card_removed(rs: &ReaderState) -> bool {
+    rs.name() != PNP_NOTIFICATION()
+        && rs.current_state().contains(State::PRESENT)
+        && rs.event_state().contains(State::EMPTY)
+}
+
+fn play_feedback(feedback: &Option<FeedbackConfig>, card: &Card, result: &Option<CardDetail>) {
+    let feedback = match feedback {
+        Some(feedback) => feedback,
+        None => return,
+    };
+    let pattern = match result {
+        Some(CardDetail::MeteUuid(_)) => feedback.pattern(&feedback.on_uuid),
+        Some(CardDetail::Plain(_)) => feedback.pattern(&feedback.on_plain),
+        None => feedback.pattern(&feedback.on_invalid),
+    };
+    if let Some(pattern) = pattern {
+        if let Err(e) = nfcfeedback::send_via_card(card, &pattern.apdu()) {
+            tracing::error!("Error playing feedback {}", e);
+        }
+    }
+}
+
 struct Service {
     ctx: Option<Context>,
+    // the context currently in use, so it can be cancelled from the outside
+    active: Arc<Mutex<Option<Context>>>,
     reader_states: Vec<ReaderState>,
     readers_buf: Vec<u8>,
-    reconnect_timeout: Duration,
+    supervisor: Supervisor,
+    feedback: Option<FeedbackConfig>,
+    hasher: Option<CardIdHasher>,
 }
 
 impl Service {
-    pub fn new() -> Service {
+    pub fn new(
+        supervisor: Supervisor,
+        feedback: Option<FeedbackConfig>,
+        hasher: Option<CardIdHasher>,
+        active: Arc<Mutex<Option<Context>>>,
+    ) -> Service {
         Service {
+            ctx: None,
+            active,
             reader_states: vec![
                 // Listen for reader insertions/removals, if supported.
                 ReaderState::new(PNP_NOTIFICATION(), State::UNAWARE),
             ],
             readers_buf: vec![0; 2048],
-            ..Default::default()
+            supervisor,
+            feedback,
+            hasher,
         }
     }
 
-    fn get_context(&mut self) -> Result<Context, Box<dyn StdError>> {
-        let ctx = match self.ctx.take() {
-            Some(ctx) => ctx,
-            None => {
-                thread::sleep(self.reconnect_timeout);
-                match Context::establish(Scope::User) {
-                    Ok(ctx) => {
-                        self.reconnect_timeout = Duration::from_secs(0);
-                        ctx
-                    }
-                    Err(e) => {
-                        self.reconnect_timeout = match self.reconnect_timeout.as_secs() {
-                            0 => Duration::from_secs(1),
-                            d => {
-                                let mut d = d * d;
-                                if d > 64 {
-                                    d = 64;
-                                }
-                                Duration::from_secs(d)
-                            }
-                        };
-                        return Err(Box::new(e));
-                    }
-                }
+    fn get_context(&mut self) -> Option<Context> {
+        if let Some(ctx) = self.ctx.take() {
+            return Some(ctx);
+        }
+        let ctx = self
+            .supervisor
+            .acquire_blocking(|| Ok(Context::establish(Scope::User)?));
+        *self.active.lock().unwrap() = ctx.clone();
+        // we might have missed the cancellation while establishing the context
+        if self.supervisor.is_cancelled() {
+            if let Some(ctx) = &ctx {
+                let _ = ctx.cancel();
             }
-        };
-        Ok(ctx)
+        }
+        ctx
     }
 
     fn fetch_next_uuid_with_context(
         &mut self,
-        ctx: Context,
-    ) -> Result<Option<CardDetail>, Box<dyn StdError>> {
+        ctx: &Context,
+    ) -> Result<NfcEvent, Box<dyn StdError>> {
         loop {
+            if self.supervisor.is_cancelled() {
+                return Err(Error::Cancelled.into());
+            }
             self.reader_states.retain(|rs| !is_dead(rs));
 
             let readers = ctx.list_readers(&mut self.readers_buf)?;
 
             for reader in readers {
                 if !self.reader_states.iter().any(|rs| rs.name() == reader) {
+                    if matches!(&self.feedback, Some(feedback) if feedback.disable_card_beep) {
+                        if let Err(e) =
+                            nfcfeedback::send_direct(ctx, reader, &nfcfeedback::DISABLE_CARD_BEEP)
+                        {
+                            tracing::warn!("Could not disable card beep of {:?} {}", reader, e);
+                        }
+                    }
                     self.reader_states
                         .push(ReaderState::new(reader, State::UNAWARE));
                 }
@@ -152,7 +223,14 @@ impl Service {
             for rs in self.reader_states.iter_mut() {
                 rs.sync_current_state();
             }
-            ctx.get_status_change(None, &mut self.reader_states)?;
+            match ctx.get_status_change(STATUS_CHANGE_TIMEOUT, &mut self.reader_states) {
+                Ok(()) => {}
+                Err(Error::Timeout) => continue,
+                Err(e) => return Err(e.into()),
+            }
+            if self.reader_states.iter().any(card_removed) {
+                return Ok(NfcEvent::Removed);
+            }
 
             let readers = ctx.list_readers(&mut self.readers_buf)?;
             let mut found_card = false;
@@ -160,9 +238,18 @@ impl Service {
                 match ctx.connect(reader, ShareMode::Shared, Protocols::ANY) {
                     Ok(card) => {
                         found_card = true;
-                        match parse_card(card)? {
-                            Some(carddetail) => return Ok(Some(carddetail)),
-                            _ => continue,
+                        let result = parse_card(&card)?;
+                        play_feedback(&self.feedback, &card, &result);
+                        // the raw UID must not leave this module
+                        let result = match (result, &self.hasher) {
+                            (Some(CardDetail::Plain(uid)), Some(hasher)) => {
+                                Some(CardDetail::Plain(hasher.hash(&uid)))
+                            }
+                            (result, _) => result,
+                        };
+                        match result {
+                            Some(carddetail) => return Ok(NfcEvent::Card(Some(carddetail))),
+                            None => continue,
                         }
                     }
                     Err(Error::NoSmartcard) => {
@@ -173,37 +260,78 @@ impl Service {
                 };
             }
             if found_card {
-                return Ok(None);
+                return Ok(NfcEvent::Card(None));
             }
         }
     }
 
-    pub fn fetch_next_uuid(&mut self) -> Result<Option<CardDetail>, Box<dyn StdError>> {
-        let ctx = self.get_context()?;
-
-        self.fetch_next_uuid_with_context(ctx)
+    /// Returns `None` once the supervisor has been cancelled
+    pub fn fetch_next_uuid(&mut self) -> Option<NfcEvent> {
+        loop {
+            let ctx = self.get_context()?;
+            match self.fetch_next_uuid_with_context(&ctx) {
+                Ok(result) => {
+                    self.ctx = Some(ctx);
+                    return Some(result);
+                }
+                Err(e) if self.supervisor.is_cancelled() => {
+                    // acquiring a new context notices the cancellation and stops
+                    tracing::debug!("nfc: shutting down ({})", e);
+                    *self.active.lock().unwrap() = None;
+                }
+                Err(e) => {
+                    *self.active.lock().unwrap() = None;
+                    self.supervisor
+                        .connection_lost(format!("Nfc Error: {:?}", e))
+                }
+            }
+        }
     }
 }
 
-pub fn run() -> Result<impl Stream<Item = Option<CardDetail>>, Box<dyn StdError>> {
+/// Reads cards on a dedicated thread, as pcsc only offers blocking calls. Cancelling
+/// the supervisor cancels the pending pcsc call and ends the stream.
+pub fn run(
+    supervisor: Supervisor,
+    feedback: Option<FeedbackConfig>,
+    hasher: Option<CardIdHasher>,
+) -> Result<impl Stream<Item = NfcEvent>, Box<dyn StdError>> {
     let (tx, mut rx) = mpsc::channel(16);
-    // hmmmm ... creating a new one seems wrong?
-    let rt = tokio::runtime::Runtime::new()?;
-    thread::spawn(move || {
-        let mut service = Service::new();
-        loop {
-            match service.fetch_next_uuid() {
-                Ok(result) => rt.block_on(async {
-                    tx.send(result).await.unwrap();
-                }),
-                Err(e) => tracing::error!("Nfc Error: {:?}", e),
+    let (done_tx, done_rx) = oneshot::channel::<()>();
+    let active: Arc<Mutex<Option<Context>>> = Arc::new(Mutex::new(None));
+
+    let cancel = supervisor.cancel_token().clone();
+    let canceller = active.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = cancel.cancelled() => {
+                if let Some(ctx) = canceller.lock().unwrap().as_ref() {
+                    if let Err(e) = ctx.cancel() {
+                        tracing::warn!("nfc: could not cancel pcsc context {}", e);
+                    }
+                }
             }
+            // the thread is gone already
+            _ = done_rx => {}
         }
     });
 
+    thread::Builder::new()
+        .name("nfc".to_string())
+        .spawn(move || {
+            let _done = done_tx;
+            let mut service = Service::new(supervisor, feedback, hasher, active);
+            while let Some(result) = service.fetch_next_uuid() {
+                if tx.blocking_send(result).is_err() {
+                    // nobody is interested anymore
+                    break;
+                }
+            }
+        })?;
+
     Ok(stream! {
-        while let Some(uuid) = rx.recv().await {
-            yield uuid;
+        while let Some(event) = rx.recv().await {
+            yield event;
         }
     })
 }
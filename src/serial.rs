@@ -0,0 +1,475 @@
+use nix::sys::termios::{
+    self, BaudRate, ControlFlags, InputFlags, SetArg, SpecialCharacterIndices,
+};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio_fd::AsyncFd;
+
+use crate::supervisor::{DeviceStatus, Interruption, Supervisor};
+
+// how often to check that the device is still there while waiting for input
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+// how long a command may take to be written to the device
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+impl FromStr for Parity {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<Parity, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" | "n" => Ok(Parity::None),
+            "even" | "e" => Ok(Parity::Even),
+            "odd" | "o" => Ok(Parity::Odd),
+            _ => Err(format!("Invalid parity {}", s).into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowControl {
+    None,
+    /// RTS/CTS
+    Hardware,
+    /// XON/XOFF
+    Software,
+}
+
+impl FromStr for FlowControl {
+    type Err = Box<dyn Error>;
+
+    fn from_str(s: &str) -> Result<FlowControl, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(FlowControl::None),
+            "hardware" | "rtscts" => Ok(FlowControl::Hardware),
+            "software" | "xonxoff" => Ok(FlowControl::Software),
+            _ => Err(format!("Invalid flow control {}", s).into()),
+        }
+    }
+}
+
+/// Line settings of a serial port. Defaults to 9600 8N1 without flow control.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct SerialConfig {
+    pub baud: u32,
+    pub data_bits: u8,
+    pub parity: Parity,
+    pub stop_bits: u8,
+    pub flow_control: FlowControl,
+}
+
+impl Default for SerialConfig {
+    fn default() -> SerialConfig {
+        SerialConfig {
+            baud: 9600,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+fn baud_rate(baud: u32) -> Result<BaudRate, Box<dyn Error>> {
+    let rate = match baud {
+        1200 => BaudRate::B1200,
+        2400 => BaudRate::B2400,
+        4800 => BaudRate::B4800,
+        9600 => BaudRate::B9600,
+        19200 => BaudRate::B19200,
+        38400 => BaudRate::B38400,
+        57600 => BaudRate::B57600,
+        115200 => BaudRate::B115200,
+        230400 => BaudRate::B230400,
+        460800 => BaudRate::B460800,
+        921600 => BaudRate::B921600,
+        _ => return Err(format!("Unsupported baud rate {}", baud).into()),
+    };
+    Ok(rate)
+}
+
+impl SerialConfig {
+    /// Put the terminal behind `fd` into raw mode using these line settings
+    pub fn apply(&self, fd: RawFd) -> Result<(), Box<dyn Error>> {
+        let mut t = termios::tcgetattr(fd)?;
+        // no echo, no line editing, no signals, no newline translation
+        termios::cfmakeraw(&mut t);
+        termios::cfsetspeed(&mut t, baud_rate(self.baud)?)?;
+
+        t.control_flags.remove(ControlFlags::CSIZE);
+        t.control_flags.insert(match self.data_bits {
+            5 => ControlFlags::CS5,
+            6 => ControlFlags::CS6,
+            7 => ControlFlags::CS7,
+            8 => ControlFlags::CS8,
+            bits => return Err(format!("Unsupported number of data bits {}", bits).into()),
+        });
+        match self.parity {
+            Parity::None => {
+                t.control_flags
+                    .remove(ControlFlags::PARENB | ControlFlags::PARODD);
+                t.input_flags.remove(InputFlags::INPCK);
+            }
+            Parity::Even => {
+                t.control_flags.insert(ControlFlags::PARENB);
+                t.control_flags.remove(ControlFlags::PARODD);
+                t.input_flags.insert(InputFlags::INPCK);
+            }
+            Parity::Odd => {
+                t.control_flags
+                    .insert(ControlFlags::PARENB | ControlFlags::PARODD);
+                t.input_flags.insert(InputFlags::INPCK);
+            }
+        }
+        match self.stop_bits {
+            1 => t.control_flags.remove(ControlFlags::CSTOPB),
+            2 => t.control_flags.insert(ControlFlags::CSTOPB),
+            bits => return Err(format!("Unsupported number of stop bits {}", bits).into()),
+        }
+        t.control_flags.remove(ControlFlags::CRTSCTS);
+        t.input_flags
+            .remove(InputFlags::IXON | InputFlags::IXOFF | InputFlags::IXANY);
+        match self.flow_control {
+            FlowControl::None => {}
+            FlowControl::Hardware => t.control_flags.insert(ControlFlags::CRTSCTS),
+            FlowControl::Software => t.input_flags.insert(InputFlags::IXON | InputFlags::IXOFF),
+        }
+        // ignore modem control lines and enable the receiver
+        t.control_flags
+            .insert(ControlFlags::CLOCAL | ControlFlags::CREAD);
+        t.control_chars[SpecialCharacterIndices::VMIN as usize] = 1;
+        t.control_chars[SpecialCharacterIndices::VTIME as usize] = 0;
+
+        termios::tcsetattr(fd, SetArg::TCSANOW, &t)?;
+        // throw away whatever was received with the old settings
+        termios::tcflush(fd, termios::FlushArg::TCIFLUSH)?;
+        Ok(())
+    }
+}
+
+// lines longer than this are garbage (wrong baud rate, noise on the line)
+const MAX_LINE_LENGTH: usize = 256;
+
+/// Reassembles newline terminated lines from whatever chunks `read` returns
+#[derive(Debug, Default)]
+pub struct LineBuffer {
+    buf: Vec<u8>,
+    discarding: bool,
+}
+
+impl LineBuffer {
+    /// Returns all lines completed by `bytes` without their line ending. Invalid
+    /// UTF-8 is replaced instead of rejecting the whole line.
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        let mut lines = Vec::new();
+        for &b in bytes {
+            if b == b'\n' {
+                if !self.discarding {
+                    let line = String::from_utf8_lossy(&self.buf);
+                    lines.push(line.trim_end_matches('\r').to_string());
+                }
+                self.buf.clear();
+                self.discarding = false;
+            } else if self.discarding {
+                continue;
+            } else if self.buf.len() >= MAX_LINE_LENGTH {
+                tracing::warn!(
+                    "Discarding overlong line {:?}",
+                    String::from_utf8_lossy(&self.buf)
+                );
+                self.buf.clear();
+                self.discarding = true;
+            } else {
+                self.buf.push(b);
+            }
+        }
+        lines
+    }
+
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.discarding = false;
+    }
+}
+
+/// Reading from a serial device which has been unplugged might just block forever.
+/// Ask the kernel whether the line hung up and whether it is still a terminal at all.
+fn check_alive(fd: RawFd) -> Result<(), Box<dyn Error>> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        // HUP and ERR are always reported
+        events: 0,
+        revents: 0,
+    };
+    if unsafe { libc::poll(&mut pollfd, 1, 0) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    if pollfd.revents & (libc::POLLHUP | libc::POLLERR | libc::POLLNVAL) != 0 {
+        return Err("device hung up".into());
+    }
+    termios::tcgetattr(fd)?;
+    Ok(())
+}
+
+struct SerialFile {
+    // we need to keep file in scope to read from fd
+    file: File,
+    fd: AsyncFd,
+}
+
+impl SerialFile {
+    pub fn new(dev: &Path, config: &SerialConfig) -> Result<SerialFile, Box<dyn Error>> {
+        // the device must never become our controlling terminal
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(dev)?;
+        let fd = file.as_raw_fd();
+        config.apply(fd)?;
+        Ok(SerialFile {
+            file,
+            fd: AsyncFd::try_from(fd)?,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    UnknownDevice,
+    InvalidCommand,
+    NotConnected,
+    Timeout,
+    Failed(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::UnknownDevice => write!(f, "unknown device"),
+            CommandError::InvalidCommand => write!(f, "commands must be a single line"),
+            CommandError::NotConnected => write!(f, "device not connected"),
+            CommandError::Timeout => write!(f, "timeout sending command"),
+            CommandError::Failed(e) => write!(f, "error sending command: {}", e),
+        }
+    }
+}
+
+impl Error for CommandError {}
+
+/// A line to send to a device, answered once it has been written
+#[derive(Debug)]
+pub struct SerialCommand {
+    line: String,
+    reply: oneshot::Sender<Result<(), CommandError>>,
+}
+
+struct CommandTarget {
+    commands: mpsc::Sender<SerialCommand>,
+    status: watch::Receiver<DeviceStatus>,
+}
+
+/// Command channels of all serial devices, keyed by device name.
+#[derive(Clone, Default)]
+pub struct SerialCommands {
+    targets: Arc<Mutex<HashMap<String, CommandTarget>>>,
+}
+
+impl SerialCommands {
+    /// Accept commands for the device supervised by `supervisor`. The returned
+    /// receiver has to be passed to its `LineReader`.
+    pub fn register(&self, supervisor: &Supervisor) -> mpsc::Receiver<SerialCommand> {
+        let (commands, rx) = mpsc::channel(8);
+        self.targets.lock().unwrap().insert(
+            supervisor.name().to_string(),
+            CommandTarget {
+                commands,
+                status: supervisor.status(),
+            },
+        );
+        rx
+    }
+
+    pub async fn send(&self, device: &str, line: &str) -> Result<(), CommandError> {
+        if line.contains(&['\r', '\n'][..]) {
+            return Err(CommandError::InvalidCommand);
+        }
+        let commands = {
+            let targets = self.targets.lock().unwrap();
+            let target = targets.get(device).ok_or(CommandError::UnknownDevice)?;
+            // don't queue commands until some time later the device is plugged in again
+            if *target.status.borrow() != DeviceStatus::Connected {
+                return Err(CommandError::NotConnected);
+            }
+            target.commands.clone()
+        };
+        let (reply, result) = oneshot::channel();
+        let command = SerialCommand {
+            line: line.to_string(),
+            reply,
+        };
+        tokio::time::timeout(COMMAND_TIMEOUT, async {
+            commands
+                .send(command)
+                .await
+                .map_err(|_| CommandError::NotConnected)?;
+            result.await.map_err(|_| CommandError::NotConnected)?
+        })
+        .await
+        .map_err(|_| CommandError::Timeout)?
+    }
+}
+
+async fn next_command(commands: &mut Option<mpsc::Receiver<SerialCommand>>) -> SerialCommand {
+    match commands {
+        Some(rx) => match rx.recv().await {
+            Some(command) => command,
+            None => std::future::pending().await,
+        },
+        None => std::future::pending().await,
+    }
+}
+
+/// Reads newline terminated lines from a serial device, reconnecting via its
+/// supervisor whenever the device is lost. Optionally writes commands back.
+pub struct LineReader {
+    dev: PathBuf,
+    config: SerialConfig,
+    serial_file: Option<SerialFile>,
+    supervisor: Supervisor,
+    lines: LineBuffer,
+    pending: VecDeque<String>,
+    commands: Option<mpsc::Receiver<SerialCommand>>,
+}
+
+impl LineReader {
+    pub fn new(
+        dev: impl Into<PathBuf>,
+        config: SerialConfig,
+        supervisor: Supervisor,
+    ) -> LineReader {
+        LineReader {
+            dev: dev.into(),
+            config,
+            serial_file: None,
+            supervisor,
+            lines: LineBuffer::default(),
+            pending: VecDeque::new(),
+            commands: None,
+        }
+    }
+
+    pub fn with_commands(mut self, commands: mpsc::Receiver<SerialCommand>) -> LineReader {
+        self.commands = Some(commands);
+        self
+    }
+
+    async fn try_read_line(&mut self) -> Result<Option<String>, Box<dyn Error>> {
+        if let Some(line) = self.pending.pop_front() {
+            return Ok(Some(line));
+        }
+        if self.serial_file.is_none() {
+            // whatever was transmitted before is incomplete
+            self.lines.clear();
+            let (dev, config) = (&self.dev, &self.config);
+            self.serial_file = self
+                .supervisor
+                .acquire(|| SerialFile::new(dev, config))
+                .await;
+            // commands sent while we were disconnected are outdated
+            if let Some(commands) = self.commands.as_mut() {
+                while let Ok(command) = commands.try_recv() {
+                    let _ = command.reply.send(Err(CommandError::NotConnected));
+                }
+            }
+        }
+        let (raw_fd, fd) = match self.serial_file.as_mut() {
+            Some(f) => (f.file.as_raw_fd(), &mut f.fd),
+            // cancelled while waiting for the device
+            None => return Ok(None),
+        };
+        let mut probe = tokio::time::interval(PROBE_INTERVAL);
+        let mut buf = [0u8; 512];
+        loop {
+            let r = tokio::select! {
+                r = fd.read(&mut buf) => r?,
+                interruption = self.supervisor.interrupted() => match interruption {
+                    Interruption::Cancelled => return Ok(None),
+                    Interruption::Removed => return Err("device removed".into()),
+                },
+                _ = probe.tick() => {
+                    check_alive(raw_fd)?;
+                    continue;
+                }
+                command = next_command(&mut self.commands) => {
+                    tracing::debug!("{:?}: Sending command {:?}", self.dev, command.line);
+                    let line = format!("{}\n", command.line);
+                    // with flow control the device may stop us forever, and we'd stop reading
+                    let write = tokio::time::timeout(COMMAND_TIMEOUT, fd.write_all(line.as_bytes()));
+                    let result = match write.await {
+                        Ok(result) => result,
+                        Err(_) => {
+                            tracing::warn!(
+                                "{:?}: Timeout sending command {:?}",
+                                self.dev,
+                                command.line
+                            );
+                            let _ = command.reply.send(Err(CommandError::Timeout));
+                            continue;
+                        }
+                    };
+                    let reply = match &result {
+                        Ok(()) => Ok(()),
+                        Err(e) => Err(CommandError::Failed(e.to_string())),
+                    };
+                    let _ = command.reply.send(reply);
+                    result?;
+                    continue;
+                }
+            };
+            // a tty only signals EOF once the other side hung up
+            if r == 0 {
+                return Err("device hung up".into());
+            }
+            self.pending.extend(self.lines.push(&buf[0..r]));
+            if let Some(line) = self.pending.pop_front() {
+                return Ok(Some(line));
+            }
+        }
+    }
+
+    /// Returns `None` once the supervisor has been cancelled
+    pub async fn read_line(&mut self) -> Option<String> {
+        loop {
+            match self.try_read_line().await {
+                Ok(line) => return line,
+                Err(e) => {
+                    self.supervisor
+                        .connection_lost(format!("Error reading {:?} {}", self.dev, e));
+                    self.serial_file = None
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use async_stream::stream;
+use futures::Stream;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use crate::serial::{LineReader, SerialCommands, SerialConfig};
+use crate::supervisor::Supervisor;
+
+#[derive(Debug, Deserialize)]
+struct RawLineMapping {
+    line: Option<String>,
+    regex: Option<String>,
+    event: String,
+    #[serde(default)]
+    redact: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum LinePattern {
+    Exact(String),
+    Regex(Regex),
+}
+
+/// Maps a received line to a named event
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RawLineMapping")]
+pub struct LineMapping {
+    pub pattern: LinePattern,
+    pub event: String,
+    /// the data identifies a person, e.g. a card id. With card id redaction it is kept
+    /// out of the logs and redacted in the event log.
+    pub redact: bool,
+}
+
+impl TryFrom<RawLineMapping> for LineMapping {
+    type Error = String;
+
+    fn try_from(raw: RawLineMapping) -> Result<LineMapping, Self::Error> {
+        let pattern = match (raw.line, raw.regex) {
+            (Some(line), None) => LinePattern::Exact(line),
+            (None, Some(regex)) => {
+                LinePattern::Regex(Regex::new(&regex).map_err(|e| e.to_string())?)
+            }
+            _ => {
+                return Err(format!(
+                    "Mapping for {} needs exactly one of line or regex",
+                    raw.event
+                ))
+            }
+        };
+        Ok(LineMapping {
+            pattern,
+            event: raw.event,
+            redact: raw.redact,
+        })
+    }
+}
+
+/// A serial device sending newline terminated commands
+#[derive(Debug, Clone, Deserialize)]
+pub struct SerialDeviceConfig {
+    pub name: String,
+    pub device: PathBuf,
+    #[serde(flatten)]
+    pub line: SerialConfig,
+    pub events: Vec<LineMapping>,
+}
+
+impl SerialDeviceConfig {
+    /// Returns the first event matching `line`. The data is the line itself or, for
+    /// regex mappings with capture groups, an object of the captured values keyed by
+    /// group name (or index for unnamed groups).
+    pub fn map_line(&self, line: &str) -> Option<SerialEvent> {
+        let line = line.trim();
+        self.events.iter().find_map(|mapping| {
+            let data = match &mapping.pattern {
+                LinePattern::Exact(expected) if expected == line => line.into(),
+                LinePattern::Exact(_) => return None,
+                LinePattern::Regex(regex) => {
+                    let captures = regex.captures(line)?;
+                    if captures.len() == 1 {
+                        line.into()
+                    } else {
+                        regex
+                            .capture_names()
+                            .enumerate()
+                            .skip(1)
+                            .map(|(i, name)| {
+                                let key = name.map_or_else(|| i.to_string(), str::to_string);
+                                let value = captures.get(i).map(|m| m.as_str().to_string());
+                                (key, value.into())
+                            })
+                            .collect::<serde_json::Map<_, _>>()
+                            .into()
+                    }
+                }
+            };
+            Some(SerialEvent {
+                device: self.name.clone(),
+                event: mapping.event.clone(),
+                data,
+                redact: mapping.redact,
+            })
+        })
+    }
+}
+
+/// Reads the device configuration from a JSON file containing a list of devices, e.g.
+///
+/// ```json
+/// [{
+///     "name": "spende",
+///     "device": "/dev/spendenknopf",
+///     "baud": 115200,
+///     "events": [
+///         { "line": "spende", "event": "spende" },
+///         { "regex": "^coin (?P<cents>\\d+)$", "event": "coin-inserted" }
+///     ]
+/// }]
+/// ```
+pub fn load_config(path: &Path) -> Result<Vec<SerialDeviceConfig>, Box<dyn Error>> {
+    let file = std::fs::File::open(path)?;
+    let configs: Vec<SerialDeviceConfig> = serde_json::from_reader(file)?;
+    check_names(&configs)?;
+    Ok(configs)
+}
+
+// commands and leases address devices by name, it has to be unique
+fn check_names(configs: &[SerialDeviceConfig]) -> Result<(), String> {
+    let mut names = HashSet::new();
+    for config in configs {
+        let name = config.name.as_str();
+        if matches!(name, "nfc" | "storno" | "barcode") || name.starts_with("barcode:") {
+            return Err(format!("Serial device name {:?} is reserved", name));
+        }
+        if !names.insert(name) {
+            return Err(format!("Serial device name {:?} is used twice", name));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct SerialEvent {
+    pub device: String,
+    pub event: String,
+    pub data: serde_json::Value,
+    pub redact: bool,
+}
+
+pub fn run(
+    config: SerialDeviceConfig,
+    supervisor: Supervisor,
+    commands: &SerialCommands,
+) -> impl Stream<Item = SerialEvent> {
+    let commands = commands.register(&supervisor);
+    stream! {
+        let mut reader =
+            LineReader::new(&config.device, config.line, supervisor).with_commands(commands);
+        while let Some(line) = reader.read_line().await {
+            if line.trim().is_empty() {
+                continue;
+            }
+            match config.map_line(&line) {
+                Some(event) => yield event,
+                None => tracing::warn!("{}: Unknown command {:?}", config.name, line),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(name: &str) -> SerialDeviceConfig {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "device": "/dev/ttyUSB0",
+            "events": [
+                { "line": "spende", "event": "spende" },
+                { "regex": "^coin (?P<cents>\\d+)$", "event": "coin-inserted" },
+                { "regex": "^card ([0-9a-f]+)( slot (\\d))?$", "event": "card", "redact": true },
+                { "regex": "^ping", "event": "ping" },
+                { "regex": "^ping pong$", "event": "pong" }
+            ]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn map_line() {
+        let config = device("spende");
+
+        let spende = config.map_line("  spende\r").unwrap();
+        assert_eq!(spende.device, "spende");
+        assert_eq!(spende.event, "spende");
+        assert_eq!(spende.data, "spende");
+        assert!(!spende.redact);
+
+        let coin = config.map_line("coin 50").unwrap();
+        assert_eq!(coin.event, "coin-inserted");
+        assert_eq!(coin.data, serde_json::json!({ "cents": "50" }));
+
+        // unnamed groups by index, groups that didn't take part are null
+        let card = config.map_line("card 04aa").unwrap();
+        assert_eq!(
+            card.data,
+            serde_json::json!({ "1": "04aa", "2": null, "3": null })
+        );
+        assert!(card.redact);
+        let card = config.map_line("card 04aa slot 2").unwrap();
+        assert_eq!(card.data["3"], "2");
+
+        // without groups the data is the line, the first mapping wins
+        let ping = config.map_line("ping pong").unwrap();
+        assert_eq!(ping.event, "ping");
+        assert_eq!(ping.data, "ping pong");
+
+        assert!(config.map_line("spende!").is_none());
+        assert!(config.map_line("coin fifty").is_none());
+    }
+
+    #[test]
+    fn mapping_needs_line_or_regex() {
+        let mapping = |mapping| serde_json::from_value::<LineMapping>(mapping);
+        assert!(mapping(serde_json::json!({ "event": "spende" })).is_err());
+        assert!(
+            mapping(serde_json::json!({ "line": "a", "regex": "a", "event": "spende" })).is_err()
+        );
+        assert!(mapping(serde_json::json!({ "regex": "(", "event": "spende" })).is_err());
+    }
+
+    #[test]
+    fn names_are_unique() {
+        assert!(check_names(&[device("spende"), device("muenzen")]).is_ok());
+        assert!(check_names(&[device("spende"), device("spende")]).is_err());
+        for reserved in ["nfc", "storno", "barcode", "barcode:event3"] {
+            assert!(check_names(&[device(reserved)]).is_err(), "{}", reserved);
+        }
+    }
+}
@@ -0,0 +1,337 @@
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::sync::watch;
+
+use crate::eventbus::Message;
+use crate::unixtime;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "id", rename_all = "kebab-case")]
+pub enum Customer {
+    /// identified via the KalkGetränk app
+    MeteUuid(String),
+    /// any other card, UID as hex
+    Plain(String),
+}
+
+/// What a cashier session reacts to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionInput {
+    Identified(Customer),
+    Scanned(String),
+    /// short press of the storno key
+    Storno,
+    /// long press of the storno key
+    StornoHeld,
+    CardRemoved,
+    /// nothing happened for a while
+    Timeout,
+}
+
+impl SessionInput {
+    /// The session input corresponding to a device event, if any
+    pub fn from_message(message: &Message) -> Option<SessionInput> {
+        let data = message.data.as_str();
+        match message.r#type.as_str() {
+            "nfc-uuid" => Some(SessionInput::Identified(Customer::MeteUuid(
+                data?.to_string(),
+            ))),
+            "nfc-plain" => Some(SessionInput::Identified(Customer::Plain(data?.to_string()))),
+            "nfc-removed" => Some(SessionInput::CardRemoved),
+            "barcode" => Some(SessionInput::Scanned(data?.to_string())),
+            "storno-released" => Some(SessionInput::Storno),
+            "storno-held" => Some(SessionInput::StornoHeld),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LineItem {
+    pub barcode: String,
+    pub scanned_at_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Session {
+    pub id: u64,
+    pub customer: Customer,
+    pub items: Vec<LineItem>,
+    pub opened_at_ms: u64,
+    pub updated_at_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpdateReason {
+    Opened,
+    ItemAdded,
+    ItemRemoved,
+    /// closed via the storno key
+    Cancelled,
+    CardRemoved,
+    Timeout,
+    /// the purchase was completed
+    CheckedOut,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionUpdate {
+    pub reason: UpdateReason,
+    /// `None` once the session is closed
+    pub session: Option<Session>,
+}
+
+struct State {
+    session: Option<Session>,
+    next_id: u64,
+}
+
+/// The purchase currently in progress.
+///
+/// Identifying with a card opens a session, scanned barcodes are added as line
+/// items. A short press of the storno key removes the last item (or closes the
+/// session if there is none), holding it closes the session right away. Taking the
+/// card off the reader or a period of inactivity closes it as well.
+#[derive(Clone)]
+pub struct SessionTracker {
+    state: Arc<Mutex<State>>,
+    // id of the open session
+    open: Arc<watch::Sender<Option<u64>>>,
+    close_on_card_removal: bool,
+}
+
+impl SessionTracker {
+    pub fn new(close_on_card_removal: bool) -> SessionTracker {
+        let (open, _) = watch::channel(None);
+        SessionTracker {
+            state: Arc::new(Mutex::new(State {
+                session: None,
+                next_id: 1,
+            })),
+            open: Arc::new(open),
+            close_on_card_removal,
+        }
+    }
+
+    pub fn current(&self) -> Option<Session> {
+        self.state.lock().unwrap().session.clone()
+    }
+
+    /// Id of the open session, changes whenever a session is opened or closed no
+    /// matter how
+    pub fn watch(&self) -> watch::Receiver<Option<u64>> {
+        self.open.subscribe()
+    }
+
+    /// Completes the current purchase, returning the closed session
+    pub fn checkout(&self) -> Option<(Session, SessionUpdate)> {
+        let session = self.state.lock().unwrap().session.take()?;
+        self.open.send_replace(None);
+        tracing::debug!("Session {:?}", UpdateReason::CheckedOut);
+        Some((
+            session,
+            SessionUpdate {
+                reason: UpdateReason::CheckedOut,
+                session: None,
+            },
+        ))
+    }
+
+    /// Reopens a session taken by `checkout` whose purchase couldn't be completed.
+    /// Returns `false` if another session has been opened in the meantime.
+    pub fn restore(&self, session: Session) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.session.is_some() {
+            return false;
+        }
+        self.open.send_replace(Some(session.id));
+        state.session = Some(session);
+        true
+    }
+
+    /// Returns the update if `input` changed the session
+    pub fn handle(&self, input: SessionInput, now: SystemTime) -> Option<SessionUpdate> {
+        let now = unixtime::millis(now);
+        let mut state = self.state.lock().unwrap();
+        let reason = match (input, state.session.as_mut()) {
+            (SessionInput::Identified(customer), session) => {
+                if matches!(&session, Some(session) if session.customer == customer) {
+                    return None;
+                }
+                let id = state.next_id;
+                state.next_id += 1;
+                // a different customer takes over
+                state.session = Some(Session {
+                    id,
+                    customer,
+                    items: Vec::new(),
+                    opened_at_ms: now,
+                    updated_at_ms: now,
+                });
+                UpdateReason::Opened
+            }
+            (_, None) => return None,
+            (SessionInput::Scanned(barcode), Some(session)) => {
+                session.items.push(LineItem {
+                    barcode,
+                    scanned_at_ms: now,
+                });
+                session.updated_at_ms = now;
+                UpdateReason::ItemAdded
+            }
+            (SessionInput::Storno, Some(session)) => match session.items.pop() {
+                Some(_) => {
+                    session.updated_at_ms = now;
+                    UpdateReason::ItemRemoved
+                }
+                None => {
+                    state.session = None;
+                    UpdateReason::Cancelled
+                }
+            },
+            (SessionInput::StornoHeld, Some(_)) => {
+                state.session = None;
+                UpdateReason::Cancelled
+            }
+            (SessionInput::CardRemoved, Some(_)) => {
+                if !self.close_on_card_removal {
+                    return None;
+                }
+                state.session = None;
+                UpdateReason::CardRemoved
+            }
+            (SessionInput::Timeout, Some(_)) => {
+                state.session = None;
+                UpdateReason::Timeout
+            }
+        };
+        tracing::debug!("Session {:?}", reason);
+        self.open
+            .send_replace(state.session.as_ref().map(|session| session.id));
+        Some(SessionUpdate {
+            reason,
+            session: state.session.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn at(ms: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(ms)
+    }
+
+    fn alice() -> SessionInput {
+        SessionInput::Identified(Customer::MeteUuid("alice".to_string()))
+    }
+
+    fn scan(barcode: &str) -> SessionInput {
+        SessionInput::Scanned(barcode.to_string())
+    }
+
+    fn barcodes(update: &SessionUpdate) -> Vec<&str> {
+        let session = update.session.as_ref().unwrap();
+        session.items.iter().map(|i| i.barcode.as_str()).collect()
+    }
+
+    #[test]
+    fn purchase() {
+        let sessions = SessionTracker::new(true);
+        let open = sessions.watch();
+        assert!(sessions.handle(scan("4001"), at(500)).is_none());
+
+        let opened = sessions.handle(alice(), at(1000)).unwrap();
+        assert_eq!(opened.reason, UpdateReason::Opened);
+        let session = opened.session.unwrap();
+        assert_eq!(session.customer, Customer::MeteUuid("alice".to_string()));
+        assert_eq!(session.opened_at_ms, 1000);
+        assert_eq!(*open.borrow(), Some(session.id));
+        // presenting the same card again changes nothing
+        assert!(sessions.handle(alice(), at(1500)).is_none());
+
+        let added = sessions.handle(scan("4001"), at(2000)).unwrap();
+        assert_eq!(added.reason, UpdateReason::ItemAdded);
+        let added = sessions.handle(scan("4002"), at(3000)).unwrap();
+        assert_eq!(barcodes(&added), ["4001", "4002"]);
+        assert_eq!(added.session.as_ref().unwrap().updated_at_ms, 3000);
+        assert_eq!(added.session.as_ref().unwrap().items[1].scanned_at_ms, 3000);
+
+        let removed = sessions.handle(SessionInput::Storno, at(4000)).unwrap();
+        assert_eq!(removed.reason, UpdateReason::ItemRemoved);
+        assert_eq!(barcodes(&removed), ["4001"]);
+
+        let (session, update) = sessions.checkout().unwrap();
+        assert_eq!(update.reason, UpdateReason::CheckedOut);
+        assert!(update.session.is_none());
+        assert_eq!(session.items.len(), 1);
+        assert_eq!(*open.borrow(), None);
+        assert!(sessions.current().is_none());
+        assert!(sessions.checkout().is_none());
+    }
+
+    #[test]
+    fn storno_closes_the_session() {
+        let sessions = SessionTracker::new(true);
+        sessions.handle(alice(), at(0));
+        let cancelled = sessions.handle(SessionInput::Storno, at(1)).unwrap();
+        assert_eq!(cancelled.reason, UpdateReason::Cancelled);
+        assert!(cancelled.session.is_none());
+
+        sessions.handle(alice(), at(2));
+        sessions.handle(scan("4001"), at(3));
+        let cancelled = sessions.handle(SessionInput::StornoHeld, at(4)).unwrap();
+        assert_eq!(cancelled.reason, UpdateReason::Cancelled);
+        assert!(sessions.current().is_none());
+    }
+
+    #[test]
+    fn card_removal_and_timeout() {
+        let sessions = SessionTracker::new(true);
+        sessions.handle(alice(), at(0));
+        let removed = sessions.handle(SessionInput::CardRemoved, at(1)).unwrap();
+        assert_eq!(removed.reason, UpdateReason::CardRemoved);
+        assert!(sessions.handle(SessionInput::Timeout, at(2)).is_none());
+
+        let sessions = SessionTracker::new(false);
+        sessions.handle(alice(), at(0));
+        assert!(sessions.handle(SessionInput::CardRemoved, at(1)).is_none());
+        let timeout = sessions.handle(SessionInput::Timeout, at(2)).unwrap();
+        assert_eq!(timeout.reason, UpdateReason::Timeout);
+        assert!(timeout.session.is_none());
+    }
+
+    #[test]
+    fn another_customer_takes_over() {
+        let sessions = SessionTracker::new(true);
+        let first = sessions.handle(alice(), at(0)).unwrap().session.unwrap();
+        sessions.handle(scan("4001"), at(1));
+        let bob = SessionInput::Identified(Customer::Plain("0a0b".to_string()));
+        let second = sessions.handle(bob, at(2)).unwrap().session.unwrap();
+        assert_ne!(first.id, second.id);
+        assert!(second.items.is_empty());
+    }
+
+    #[test]
+    fn restore_after_failed_checkout() {
+        let sessions = SessionTracker::new(true);
+        let open = sessions.watch();
+        sessions.handle(alice(), at(0));
+        sessions.handle(scan("4001"), at(1));
+        let (session, _) = sessions.checkout().unwrap();
+
+        assert!(sessions.restore(session.clone()));
+        assert_eq!(*open.borrow(), Some(session.id));
+        assert_eq!(sessions.current().unwrap().items.len(), 1);
+
+        // somebody else got there first
+        let (session, _) = sessions.checkout().unwrap();
+        sessions.handle(alice(), at(2));
+        assert!(!sessions.restore(session));
+        assert!(sessions.current().unwrap().items.is_empty());
+    }
+}
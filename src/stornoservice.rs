@@ -1,129 +1,169 @@
 use async_stream::stream;
 use futures::Stream;
-use nix::sys::termios;
-use std::convert::TryFrom;
-use std::error::Error;
-use std::fs::File;
-use std::os::unix::io::AsRawFd;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 use std::time::{Duration, Instant};
-use tokio::io::AsyncReadExt;
-use tokio::time::sleep;
-use tokio_fd::AsyncFd;
 
-const STORNO: &str = "storno\n";
-const STORNOEND: &str = "stornoend\n";
+use crate::serial::{LineReader, SerialCommands, SerialConfig};
+use crate::supervisor::Supervisor;
 
-struct StornoFile {
-    // we need to keep file in scope to read from fd
-    _file: File,
-    fd: AsyncFd,
-}
-
-impl StornoFile {
-    pub fn new(dev: &Path) -> Result<StornoFile, Box<dyn Error>> {
-        let file = File::open(dev)?;
-        let fd = file.as_raw_fd();
-        let mut t = termios::tcgetattr(fd)?;
-        termios::cfsetispeed(&mut t, termios::BaudRate::B9600)?;
-        Ok(StornoFile {
-            _file: file,
-            fd: AsyncFd::try_from(fd)?,
-        })
-    }
+const STORNO: &str = "storno";
+const STORNOEND: &str = "stornoend";
 
-    pub fn fd_mut(&mut self) -> &mut AsyncFd {
-        &mut self.fd
-    }
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StornoEvent {
+    Pressed,
+    /// released before the hold threshold
+    Released,
+    /// released after being held for at least the hold threshold
+    Held(Duration),
 }
 
-struct StornoReader {
-    dev: PathBuf,
-    first_sleep_secs: Option<u64>,
-    storno_file: Option<StornoFile>,
+/// Parser for the line based protocol of the storno key: it sends `storno` when
+/// the key is pressed and `stornoend` when it is released again.
+pub struct StornoParser {
+    pressed_at: Option<Instant>,
+    hold_threshold: Duration,
 }
 
-impl StornoReader {
-    pub fn new(dev: impl Into<PathBuf>) -> StornoReader {
-        StornoReader {
-            dev: dev.into(),
-            storno_file: None,
-            first_sleep_secs: Some(0),
+impl StornoParser {
+    pub fn new(hold_threshold: Duration) -> StornoParser {
+        StornoParser {
+            pressed_at: None,
+            hold_threshold,
         }
     }
 
-    pub async fn acquire_storno_fd(&mut self) -> &mut AsyncFd {
-        if self.storno_file.is_none() {
-            // special case for the very first call (try to acquire immediately)
-            // if the fd somehow became None after the first call we had an error
-            // and should wait before trying again
-            let mut sleep_secs = self.first_sleep_secs.take().unwrap_or(1);
-            while self.storno_file.is_none() {
-                sleep(Duration::from_secs(sleep_secs)).await;
-                self.storno_file = match StornoFile::new(&self.dev) {
-                    Ok(fd) => Some(fd),
-                    Err(e) => {
-                        tracing::error!("Error accessing storno file {}", e);
-                        if sleep_secs == 0 {
-                            sleep_secs = 1;
-                        } else {
-                            sleep_secs = sleep_secs * 2;
-                            if sleep_secs > 4 {
-                                sleep_secs = 4;
-                            }
-                        }
-                        None
-                    }
-                }
+    pub fn parse(&mut self, line: &str, now: Instant) -> Option<StornoEvent> {
+        match line.trim() {
+            STORNO => {
+                self.pressed_at = Some(now);
+                Some(StornoEvent::Pressed)
             }
-        }
-
-        self.storno_file.as_mut().unwrap().fd_mut()
-    }
-
-    pub async fn try_read_storno(&mut self) -> Result<(), Box<dyn Error>> {
-        let fd = self.acquire_storno_fd().await;
-        let mut buf = [0u8; 512];
-        // sometimes the key is triggering storno and stornoend at the same time
-        // check that there is some time difference between both!
-        // it seems that releasing the key doesn't trigger storno
-        // so the code might be stupid but works for me :S
-        let mut storno = Instant::now();
-        let min_storno_time = Duration::from_millis(50);
-        loop {
-            // this currently blocks forever even if you pull out the device. unclear how to solve that
-            let r = fd.read(&mut buf).await?;
-            if r == 0 {
-                continue;
-            }
-            let st = std::str::from_utf8(&buf[0..r])?;
-            if st.contains(STORNO) {
-                storno = Instant::now();
+            STORNOEND => {
+                let held = self
+                    .pressed_at
+                    .take()
+                    .map(|pressed_at| now.duration_since(pressed_at))
+                    .filter(|held| *held >= self.hold_threshold);
+                Some(match held {
+                    Some(held) => StornoEvent::Held(held),
+                    None => StornoEvent::Released,
+                })
             }
-            if st.contains(STORNOEND) && storno.elapsed() >= min_storno_time {
-                return Ok(());
+            "" => None,
+            line => {
+                tracing::warn!("Unknown storno command {:?}", line);
+                None
             }
         }
     }
+}
 
-    pub async fn read_storno(&mut self) -> () {
-        loop {
-            match self.try_read_storno().await {
-                Ok(()) => return (),
-                Err(e) => {
-                    tracing::error!("Error reading storno {}", e);
-                    self.storno_file = None
-                }
+pub fn run(
+    dev: impl Into<PathBuf>,
+    config: SerialConfig,
+    hold_threshold: Duration,
+    supervisor: Supervisor,
+    commands: &SerialCommands,
+) -> impl Stream<Item = StornoEvent> {
+    let commands = commands.register(&supervisor);
+    stream! {
+        let mut reader = LineReader::new(dev, config, supervisor).with_commands(commands);
+        let mut parser = StornoParser::new(hold_threshold);
+        while let Some(line) = reader.read_line().await {
+            if let Some(event) = parser.parse(&line, Instant::now()) {
+                yield event;
             }
         }
     }
 }
 
-pub fn run(dev: impl Into<PathBuf>) -> impl Stream<Item = ()> {
-    stream! {
-        let mut reader = StornoReader::new(dev);
-        loop {
-            yield reader.read_storno().await;
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::LineBuffer;
+
+    fn feed(
+        lines: &mut LineBuffer,
+        parser: &mut StornoParser,
+        bytes: &[u8],
+        now: Instant,
+    ) -> Vec<StornoEvent> {
+        lines
+            .push(bytes)
+            .iter()
+            .filter_map(|line| parser.parse(line, now))
+            .collect()
+    }
+
+    #[test]
+    fn split_reads() {
+        let mut lines = LineBuffer::default();
+        let mut parser = StornoParser::new(Duration::from_secs(1));
+        let now = Instant::now();
+        assert_eq!(feed(&mut lines, &mut parser, b"sto", now), []);
+        assert_eq!(
+            feed(&mut lines, &mut parser, b"rno\r\nstorn", now),
+            [StornoEvent::Pressed]
+        );
+        assert_eq!(feed(&mut lines, &mut parser, b"oe", now), []);
+        assert_eq!(
+            feed(&mut lines, &mut parser, b"nd\n", now),
+            [StornoEvent::Released]
+        );
+    }
+
+    #[test]
+    fn press_and_release_in_one_read() {
+        let mut lines = LineBuffer::default();
+        let mut parser = StornoParser::new(Duration::from_secs(1));
+        assert_eq!(
+            feed(
+                &mut lines,
+                &mut parser,
+                b"storno\nstornoend\n",
+                Instant::now()
+            ),
+            [StornoEvent::Pressed, StornoEvent::Released]
+        );
+    }
+
+    #[test]
+    fn garbage_is_skipped() {
+        let mut lines = LineBuffer::default();
+        let mut parser = StornoParser::new(Duration::from_secs(1));
+        assert_eq!(
+            feed(
+                &mut lines,
+                &mut parser,
+                b"\xff\xfe\nstorno\n",
+                Instant::now()
+            ),
+            [StornoEvent::Pressed]
+        );
+    }
+
+    #[test]
+    fn hold_threshold() {
+        let mut parser = StornoParser::new(Duration::from_millis(1000));
+        let pressed = Instant::now();
+
+        assert_eq!(parser.parse("storno", pressed), Some(StornoEvent::Pressed));
+        assert_eq!(
+            parser.parse("stornoend", pressed + Duration::from_millis(999)),
+            Some(StornoEvent::Released)
+        );
+
+        assert_eq!(parser.parse("storno", pressed), Some(StornoEvent::Pressed));
+        assert_eq!(
+            parser.parse("stornoend", pressed + Duration::from_millis(1000)),
+            Some(StornoEvent::Held(Duration::from_millis(1000)))
+        );
+
+        // a release without press, e.g. after reconnecting, is never a hold
+        assert_eq!(
+            parser.parse("stornoend", pressed + Duration::from_secs(5)),
+            Some(StornoEvent::Released)
+        );
     }
 }
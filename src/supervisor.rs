@@ -0,0 +1,631 @@
+use rand::Rng;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, watch};
+use tokio_util::sync::CancellationToken;
+
+use crate::hotplug::{Hotplug, HotplugEvent};
+
+/// Exponential backoff with jitter and an upper bound.
+///
+/// The first delay after a reset is `initial`, every following delay is
+/// multiplied by `factor` until `max` is reached.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    factor: u32,
+    jitter: f64,
+    current: Option<Duration>,
+}
+
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff::new(Duration::from_secs(1), Duration::from_secs(4))
+    }
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial,
+            max: max.max(initial),
+            factor: 2,
+            jitter: 0.2,
+            current: None,
+        }
+    }
+
+    /// Multiplier applied to the delay after every failed attempt.
+    pub fn with_factor(mut self, factor: u32) -> Backoff {
+        self.factor = factor.max(1);
+        self
+    }
+
+    /// Randomly spread each delay by up to `jitter` (0.0 - 1.0) in both directions
+    /// so that devices sharing a bus don't all retry at the same instant.
+    pub fn with_jitter(mut self, jitter: f64) -> Backoff {
+        // NaN would get through clamp
+        self.jitter = if jitter.is_nan() {
+            0.0
+        } else {
+            jitter.clamp(0.0, 1.0)
+        };
+        self
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = match self.current {
+            None => self.initial,
+            Some(d) => (d * self.factor).min(self.max),
+        };
+        self.current = Some(delay);
+        if self.jitter == 0.0 || delay.is_zero() {
+            return delay;
+        }
+        let spread = rand::thread_rng().gen_range(-self.jitter..=self.jitter);
+        delay.mul_f64(1.0 + spread).min(self.max)
+    }
+
+    pub fn reset(&mut self) {
+        self.current = None;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "kebab-case")]
+pub enum DeviceStatus {
+    Connecting,
+    Connected,
+    Disconnected { error: String, retry_in_ms: u64 },
+    Stopped,
+}
+
+/// Why a driver has to stop reading from its device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interruption {
+    Cancelled,
+    Removed,
+}
+
+struct DeviceWatch {
+    dev: PathBuf,
+    events: broadcast::Receiver<HotplugEvent>,
+}
+
+impl DeviceWatch {
+    async fn wait_for(&mut self, added: bool) {
+        loop {
+            match self.events.recv().await {
+                Ok(HotplugEvent::Added(path)) if added && path == self.dev => return,
+                Ok(HotplugEvent::Removed(path)) if !added && path == self.dev => return,
+                Ok(_) => continue,
+                // we missed something. check for ourselves
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    if self.dev.exists() == added {
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+            }
+        }
+    }
+
+    fn drain(&mut self) {
+        while !matches!(
+            self.events.try_recv(),
+            Err(broadcast::error::TryRecvError::Empty | broadcast::error::TryRecvError::Closed)
+        ) {}
+    }
+}
+
+async fn wait_for(watch: &mut Option<DeviceWatch>, added: bool) {
+    match watch {
+        Some(watch) => watch.wait_for(added).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Keeps a single device connected.
+///
+/// Drivers call `acquire` whenever they need a handle to their device and
+/// `connection_lost` when the handle turned out to be broken. The supervisor
+/// takes care of waiting between attempts, publishing the current status and
+/// stopping once the cancellation token fired.
+///
+/// With hotplug detection enabled the backoff delay is cut short as soon as the
+/// device node appears, and `interrupted` fires once it is removed again.
+pub struct Supervisor {
+    name: String,
+    backoff: Backoff,
+    cancel: CancellationToken,
+    status: watch::Sender<DeviceStatus>,
+    pending_delay: Option<Duration>,
+    hotplug: Option<DeviceWatch>,
+    transient: Option<PathBuf>,
+}
+
+impl Supervisor {
+    pub fn new(name: impl Into<String>, backoff: Backoff, cancel: CancellationToken) -> Supervisor {
+        let (status, _) = watch::channel(DeviceStatus::Connecting);
+        Supervisor {
+            name: name.into(),
+            backoff,
+            cancel,
+            status,
+            pending_delay: None,
+            hotplug: None,
+            transient: None,
+        }
+    }
+
+    pub fn with_hotplug(mut self, dev: impl Into<PathBuf>, hotplug: &Hotplug) -> Supervisor {
+        let dev = dev.into();
+        let events = hotplug.watch(&dev);
+        self.hotplug = Some(DeviceWatch { dev, events });
+        self
+    }
+
+    /// Give up once `dev` is gone instead of waiting for it to come back. Used for
+    /// dynamically discovered devices whose node name might be reused by a completely
+    /// different device later on.
+    pub fn transient(mut self, dev: impl Into<PathBuf>) -> Supervisor {
+        self.transient = Some(dev.into());
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn status(&self) -> watch::Receiver<DeviceStatus> {
+        self.status.subscribe()
+    }
+
+    pub fn cancel_token(&self) -> &CancellationToken {
+        &self.cancel
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Call `open` until it succeeds. Returns `None` if the supervisor was cancelled
+    /// in the meantime or its transient device is gone.
+    pub async fn acquire<T>(
+        &mut self,
+        mut open: impl FnMut() -> Result<T, Box<dyn Error>>,
+    ) -> Option<T> {
+        loop {
+            if matches!(&self.transient, Some(dev) if !dev.exists()) {
+                self.stop();
+                return None;
+            }
+            if let Some(delay) = self.pending_delay.take() {
+                let cancel = self.cancel.clone();
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {},
+                    _ = cancel.cancelled() => {},
+                    _ = wait_for(&mut self.hotplug, true) => {},
+                }
+            }
+            if self.cancel.is_cancelled() {
+                self.stop();
+                return None;
+            }
+            if let Some(handle) = self.try_open(&mut open) {
+                return Some(handle);
+            }
+        }
+    }
+
+    /// Same as `acquire` for drivers living on a blocking thread.
+    pub fn acquire_blocking<T>(
+        &mut self,
+        mut open: impl FnMut() -> Result<T, Box<dyn Error>>,
+    ) -> Option<T> {
+        loop {
+            if let Some(delay) = self.pending_delay.take() {
+                // the token has no blocking wait so check it every now and then
+                let deadline = Instant::now() + delay;
+                while !self.cancel.is_cancelled() {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    std::thread::sleep((deadline - now).min(Duration::from_millis(100)));
+                }
+            }
+            if self.cancel.is_cancelled() {
+                self.stop();
+                return None;
+            }
+            if let Some(handle) = self.try_open(&mut open) {
+                return Some(handle);
+            }
+        }
+    }
+
+    /// Resolves once the driver should stop reading: either the supervisor was
+    /// cancelled or the device node was removed.
+    pub async fn interrupted(&mut self) -> Interruption {
+        let cancel = self.cancel.clone();
+        tokio::select! {
+            _ = cancel.cancelled() => Interruption::Cancelled,
+            _ = wait_for(&mut self.hotplug, false) => Interruption::Removed,
+        }
+    }
+
+    /// Report that a previously acquired handle is broken. The next `acquire`
+    /// waits for the backoff delay before trying again.
+    pub fn connection_lost(&mut self, error: impl Display) {
+        tracing::error!("{}: connection lost: {}", self.name, error);
+        self.failed(error.to_string());
+    }
+
+    fn try_open<T>(&mut self, open: &mut impl FnMut() -> Result<T, Box<dyn Error>>) -> Option<T> {
+        self.status.send_replace(DeviceStatus::Connecting);
+        match open() {
+            Ok(handle) => {
+                tracing::info!("{}: connected", self.name);
+                self.backoff.reset();
+                // anything that happened before is irrelevant for the new handle
+                if let Some(watch) = self.hotplug.as_mut() {
+                    watch.drain();
+                }
+                self.status.send_replace(DeviceStatus::Connected);
+                Some(handle)
+            }
+            Err(e) => {
+                tracing::error!("{}: error connecting: {}", self.name, e);
+                self.failed(e.to_string());
+                None
+            }
+        }
+    }
+
+    fn failed(&mut self, error: String) {
+        let delay = self.backoff.next_delay();
+        self.pending_delay = Some(delay);
+        self.status.send_replace(DeviceStatus::Disconnected {
+            error,
+            retry_in_ms: delay.as_millis() as u64,
+        });
+    }
+
+    fn stop(&mut self) {
+        tracing::info!("{}: stopped", self.name);
+        self.status.send_replace(DeviceStatus::Stopped);
+    }
+}
+
+/// Status of all supervised devices, keyed by device name.
+#[derive(Clone)]
+pub struct StatusBoard {
+    devices: Arc<Mutex<BTreeMap<String, watch::Receiver<DeviceStatus>>>>,
+    // bumped whenever a device is added or removed
+    members: Arc<watch::Sender<()>>,
+}
+
+impl Default for StatusBoard {
+    fn default() -> StatusBoard {
+        StatusBoard {
+            devices: Arc::default(),
+            members: Arc::new(watch::channel(()).0),
+        }
+    }
+}
+
+impl StatusBoard {
+    pub fn register(&self, supervisor: &Supervisor) {
+        self.devices
+            .lock()
+            .unwrap()
+            .insert(supervisor.name().to_string(), supervisor.status());
+        self.members.send_replace(());
+    }
+
+    pub fn unregister(&self, name: &str) {
+        self.devices.lock().unwrap().remove(name);
+        self.members.send_replace(());
+    }
+
+    /// Notifies about changes from now on, see `StatusWatch::changed`
+    pub fn watch(&self) -> StatusWatch {
+        let mut watch = StatusWatch {
+            board: self.clone(),
+            members: self.members.subscribe(),
+            devices: Vec::new(),
+        };
+        watch.resubscribe();
+        watch
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, DeviceStatus> {
+        self.devices
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, status)| (name.clone(), status.borrow().clone()))
+            .collect()
+    }
+}
+
+/// Change notification of a `StatusBoard`
+pub struct StatusWatch {
+    board: StatusBoard,
+    members: watch::Receiver<()>,
+    devices: Vec<watch::Receiver<DeviceStatus>>,
+}
+
+impl StatusWatch {
+    fn resubscribe(&mut self) {
+        self.members.mark_unchanged();
+        self.devices = self
+            .board
+            .devices
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        // the copies start out where the board's receivers are, i.e. long ago
+        for status in &mut self.devices {
+            status.mark_unchanged();
+        }
+    }
+
+    /// Waits until a device was added, removed or changed its status since the last
+    /// call. Take a `snapshot` of the board afterwards.
+    pub async fn changed(&mut self) {
+        loop {
+            // the board is alive as long as we hold it, `members` never closes
+            if self.devices.is_empty() {
+                let _ = self.members.changed().await;
+                return self.resubscribe();
+            }
+            let statuses = self
+                .devices
+                .iter_mut()
+                .map(|status| Box::pin(status.changed()));
+            let stopped = tokio::select! {
+                _ = self.members.changed() => None,
+                (changed, i, _) = futures::future::select_all(statuses) => match changed {
+                    Ok(()) => return,
+                    Err(_) => Some(i),
+                },
+            };
+            match stopped {
+                // the supervisor is gone, its last status stays as it is
+                Some(i) => {
+                    self.devices.remove(i);
+                }
+                None => return self.resubscribe(),
+            }
+        }
+    }
+}
+
+/// Creates supervisors sharing the same backoff settings, cancellation and status board.
+#[derive(Clone)]
+pub struct Supervisors {
+    backoff: Backoff,
+    cancel: CancellationToken,
+    board: StatusBoard,
+    hotplug: Option<Hotplug>,
+}
+
+impl Supervisors {
+    pub fn new(
+        backoff: Backoff,
+        cancel: CancellationToken,
+        board: StatusBoard,
+        hotplug: Option<Hotplug>,
+    ) -> Supervisors {
+        Supervisors {
+            backoff,
+            cancel,
+            board,
+            hotplug,
+        }
+    }
+
+    pub fn supervisor(&self, name: &str) -> Supervisor {
+        let supervisor = Supervisor::new(name, self.backoff.clone(), self.cancel.child_token());
+        self.board.register(&supervisor);
+        supervisor
+    }
+
+    /// Supervisor for a device node, using hotplug detection if available
+    pub fn device_supervisor(&self, name: &str, dev: &Path) -> Supervisor {
+        let supervisor = Supervisor::new(name, self.backoff.clone(), self.cancel.child_token());
+        let supervisor = match &self.hotplug {
+            Some(hotplug) => supervisor.with_hotplug(dev, hotplug),
+            None => supervisor,
+        };
+        self.board.register(&supervisor);
+        supervisor
+    }
+
+    pub fn hotplug(&self) -> Option<&Hotplug> {
+        self.hotplug.as_ref()
+    }
+
+    pub fn cancel_token(&self) -> &CancellationToken {
+        &self.cancel
+    }
+
+    pub fn board(&self) -> &StatusBoard {
+        &self.board
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_grows_until_max() {
+        let mut backoff =
+            Backoff::new(Duration::from_millis(100), Duration::from_millis(1000)).with_jitter(0.0);
+        let delays: Vec<_> = (0..6).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn backoff_factor() {
+        let mut backoff = Backoff::new(Duration::from_millis(10), Duration::from_secs(10))
+            .with_factor(3)
+            .with_jitter(0.0);
+        let delays: Vec<_> = (0..4).map(|_| backoff.next_delay().as_millis()).collect();
+        assert_eq!(delays, [10, 30, 90, 270]);
+    }
+
+    #[test]
+    fn backoff_jitter_stays_within_bounds() {
+        let mut backoff = Backoff::new(Duration::from_millis(1000), Duration::from_millis(1500))
+            .with_jitter(0.25);
+        for _ in 0..100 {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_millis(750), "{:?}", delay);
+            assert!(delay <= Duration::from_millis(1500), "{:?}", delay);
+            backoff.reset();
+        }
+        // the cap holds even if jitter would go above it
+        backoff.next_delay();
+        for _ in 0..100 {
+            let delay = backoff.next_delay();
+            assert!(delay >= Duration::from_millis(1125), "{:?}", delay);
+            assert!(delay <= Duration::from_millis(1500), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn backoff_ignores_invalid_jitter() {
+        for jitter in [f64::NAN, -0.5] {
+            let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_secs(1))
+                .with_jitter(jitter);
+            assert_eq!(backoff.next_delay(), Duration::from_millis(100));
+        }
+    }
+
+    fn supervisor(cancel: &CancellationToken) -> Supervisor {
+        let backoff =
+            Backoff::new(Duration::from_millis(100), Duration::from_millis(1000)).with_jitter(0.0);
+        Supervisor::new("nfc", backoff, cancel.clone())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn acquire_retries_with_backoff() {
+        let mut supervisor = supervisor(&CancellationToken::new());
+        let status = supervisor.status();
+        let start = tokio::time::Instant::now();
+        let mut attempts = Vec::new();
+        let handle = supervisor
+            .acquire(|| {
+                attempts.push(start.elapsed().as_millis());
+                match attempts.len() {
+                    1 | 2 => Err("no such device".into()),
+                    _ => Ok("handle"),
+                }
+            })
+            .await;
+        assert_eq!(handle, Some("handle"));
+        assert_eq!(attempts, [0, 100, 300]);
+        assert_eq!(*status.borrow(), DeviceStatus::Connected);
+
+        // connecting successfully starts the backoff over
+        supervisor.connection_lost("unplugged");
+        assert_eq!(
+            *status.borrow(),
+            DeviceStatus::Disconnected {
+                error: "unplugged".to_string(),
+                retry_in_ms: 100,
+            }
+        );
+        let start = tokio::time::Instant::now();
+        supervisor.acquire(|| Ok(())).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(100));
+        assert_eq!(*status.borrow(), DeviceStatus::Connected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn acquire_stops_when_cancelled() {
+        let cancel = CancellationToken::new();
+        let mut supervisor = supervisor(&cancel);
+        let status = supervisor.status();
+        let cancel_later = async {
+            tokio::time::sleep(Duration::from_millis(250)).await;
+            cancel.cancel();
+        };
+        let mut attempts = 0;
+        let (handle, _) = tokio::join!(
+            supervisor.acquire(|| -> Result<(), Box<dyn Error>> {
+                attempts += 1;
+                Err("no such device".into())
+            }),
+            cancel_later
+        );
+        assert_eq!(handle, None);
+        // at 0 and 100ms, the next one would have been at 300ms
+        assert_eq!(attempts, 2);
+        assert_eq!(*status.borrow(), DeviceStatus::Stopped);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transient_device_stops_once_gone() {
+        let dev = std::env::temp_dir().join(format!("supervisor-test-{}", std::process::id()));
+        std::fs::write(&dev, "").unwrap();
+        let mut supervisor = supervisor(&CancellationToken::new()).transient(&dev);
+        let status = supervisor.status();
+        supervisor.acquire(|| Ok(())).await.unwrap();
+
+        std::fs::remove_file(&dev).unwrap();
+        supervisor.connection_lost("unplugged");
+        let mut attempts = 0;
+        let handle = supervisor
+            .acquire(|| {
+                attempts += 1;
+                Ok(())
+            })
+            .await;
+        assert_eq!(handle, None);
+        assert_eq!(attempts, 0);
+        assert_eq!(*status.borrow(), DeviceStatus::Stopped);
+    }
+
+    async fn changed(watch: &mut StatusWatch) -> bool {
+        tokio::time::timeout(Duration::from_millis(50), watch.changed())
+            .await
+            .is_ok()
+    }
+
+    #[tokio::test]
+    async fn status_watch() {
+        let board = StatusBoard::default();
+        let mut watch = board.watch();
+        let mut supervisor = Supervisor::new("nfc", Backoff::default(), CancellationToken::new());
+        board.register(&supervisor);
+        assert!(changed(&mut watch).await);
+        assert!(!changed(&mut watch).await);
+
+        supervisor.connection_lost("unplugged");
+        assert!(changed(&mut watch).await);
+        assert!(!changed(&mut watch).await);
+
+        // a dropped supervisor keeps its last status
+        drop(supervisor);
+        assert!(!changed(&mut watch).await);
+        board.unregister("nfc");
+        assert!(changed(&mut watch).await);
+        assert!(board.snapshot().is_empty());
+    }
+}
@@ -0,0 +1,12 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Milliseconds since the unix epoch, 0 for anything before
+pub fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+pub fn now_millis() -> u64 {
+    millis(SystemTime::now())
+}
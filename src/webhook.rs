@@ -0,0 +1,436 @@
+use hmac::{Hmac, Mac};
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Method, Request, Uri};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+use crate::eventbus::{EventBus, Observer};
+use crate::hex;
+use crate::supervisor::Backoff;
+use crate::unixtime;
+
+const QUEUE_SIZE: usize = 64;
+const TIMEOUT: Duration = Duration::from_secs(5);
+// how long the deliveries queued before a shutdown may still take
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// only deliver these event types, everything if not set
+    #[serde(default)]
+    pub types: Option<BTreeSet<String>>,
+    /// key of the `X-Kasse-Signature` HMAC
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+}
+
+/// Reads a JSON file like
+///
+/// ```json
+/// [{
+///     "url": "http://localhost:8080/hook",
+///     "types": ["barcode", "session-updated"],
+///     "secret": "..."
+/// }]
+/// ```
+pub fn load_config(path: &Path) -> Result<Vec<WebhookConfig>, Box<dyn Error>> {
+    let file = File::open(path)?;
+    Ok(serde_json::from_reader(file)?)
+}
+
+/// Body of a webhook request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    /// unique across restarts, receivers can use it to skip duplicates
+    pub id: String,
+    pub at_ms: u64,
+    pub device: Option<String>,
+    pub r#type: String,
+    pub data: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailedDelivery {
+    pub url: String,
+    pub delivery: Delivery,
+    pub attempts: u32,
+    pub error: String,
+    pub failed_at_ms: u64,
+}
+
+/// Deliveries that could not be made, kept as JSON lines file
+pub struct FailureStore {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl FailureStore {
+    pub fn open(path: &Path) -> io::Result<FailureStore> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(FailureStore {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    async fn add(self: &Arc<Self>, failure: FailedDelivery) {
+        let mut line = serde_json::to_vec(&failure).unwrap();
+        line.push(b'\n');
+        let store = self.clone();
+        let result =
+            tokio::task::spawn_blocking(move || store.file.lock().unwrap().write_all(&line)).await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!("Error storing failed webhook delivery {}", e),
+            Err(e) => tracing::error!("Error storing failed webhook delivery {}", e),
+        }
+    }
+
+    pub fn list(&self) -> io::Result<Vec<FailedDelivery>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut failures = Vec::new();
+        for line in BufReader::new(file).lines() {
+            // the store is only appended to, a crash can at most cut off the last failure
+            if let Ok(failure) = serde_json::from_str(&line?) {
+                failures.push(failure);
+            }
+        }
+        Ok(failures)
+    }
+}
+
+enum DeliveryError {
+    /// worth trying again
+    Transient(String),
+    Permanent(String),
+}
+
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    format!("sha256={}", hex::encode(&mac.finalize().into_bytes()))
+}
+
+struct Webhook {
+    config: WebhookConfig,
+    uri: Uri,
+    client: Client<HttpConnector>,
+    failures: Option<Arc<FailureStore>>,
+}
+
+impl Webhook {
+    async fn post(&self, delivery: &Delivery) -> Result<(), DeliveryError> {
+        let body = serde_json::to_vec(delivery).unwrap();
+        let mut request = Request::builder()
+            .method(Method::POST)
+            .uri(self.uri.clone())
+            .header("Content-Type", "application/json")
+            .header("X-Kasse-Event", &delivery.r#type)
+            .header("X-Kasse-Delivery", &delivery.id);
+        if let Some(secret) = &self.config.secret {
+            request = request.header("X-Kasse-Signature", signature(secret, &body));
+        }
+        let request = request
+            .body(Body::from(body))
+            .map_err(|e| DeliveryError::Permanent(e.to_string()))?;
+        let response = tokio::time::timeout(TIMEOUT, self.client.request(request))
+            .await
+            .map_err(|_| DeliveryError::Transient("request timed out".to_string()))?
+            .map_err(|e| DeliveryError::Transient(e.to_string()))?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(())
+        } else if status.is_server_error() || status.as_u16() == 429 {
+            Err(DeliveryError::Transient(format!(
+                "unexpected status {}",
+                status
+            )))
+        } else {
+            Err(DeliveryError::Permanent(format!(
+                "unexpected status {}",
+                status
+            )))
+        }
+    }
+
+    async fn failed(&self, delivery: Delivery, attempts: u32, error: String) {
+        tracing::warn!(
+            "Webhook delivery {} to {} failed: {}",
+            delivery.id,
+            self.config.url,
+            error
+        );
+        if let Some(failures) = &self.failures {
+            failures
+                .add(FailedDelivery {
+                    url: self.config.url.clone(),
+                    delivery,
+                    attempts,
+                    error,
+                    failed_at_ms: unixtime::now_millis(),
+                })
+                .await;
+        }
+    }
+
+    // after `cancel` there are no more retries, after `give_up` no more attempts
+    async fn deliver(
+        &self,
+        delivery: Delivery,
+        cancel: &CancellationToken,
+        give_up: &CancellationToken,
+    ) {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let result = tokio::select! {
+                result = self.post(&delivery) => result,
+                _ = give_up.cancelled() => {
+                    return self.failed(delivery, attempts, "shutting down".to_string()).await;
+                }
+            };
+            let error = match result {
+                Ok(()) => return,
+                Err(DeliveryError::Permanent(e)) => {
+                    return self.failed(delivery, attempts, e).await;
+                }
+                Err(DeliveryError::Transient(e)) => e,
+            };
+            if attempts >= self.config.max_attempts || cancel.is_cancelled() {
+                return self.failed(delivery, attempts, error).await;
+            }
+            let delay = backoff.next_delay();
+            tracing::debug!(
+                "Webhook delivery {} to {} failed, retrying in {:?}: {}",
+                delivery.id,
+                self.config.url,
+                delay,
+                error
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {},
+                // one last attempt right away
+                _ = cancel.cancelled() => {},
+            }
+        }
+    }
+
+    // one at a time, so a receiver sees the events in order. the queue only ends once
+    // dispatch stopped, everything sent before the shutdown is still attempted.
+    async fn run(
+        self,
+        mut queue: mpsc::Receiver<Delivery>,
+        cancel: CancellationToken,
+        give_up: CancellationToken,
+    ) {
+        while let Some(delivery) = queue.recv().await {
+            if give_up.is_cancelled() {
+                self.failed(delivery, 0, "shutting down".to_string()).await;
+                continue;
+            }
+            self.deliver(delivery, &cancel, &give_up).await;
+        }
+    }
+}
+
+/// Posts events to the configured webhooks until `cancel` fires. Every webhook has
+/// its own queue, a slow receiver only delays its own deliveries.
+pub fn start(
+    configs: Vec<WebhookConfig>,
+    failures: Option<Arc<FailureStore>>,
+    events: &EventBus,
+    cancel: CancellationToken,
+) -> Result<JoinHandle<()>, Box<dyn Error>> {
+    let client = Client::new();
+    let mut webhooks = Vec::new();
+    for config in configs {
+        let uri = config
+            .url
+            .parse::<Uri>()
+            .map_err(|e| format!("invalid webhook url {:?}: {}", config.url, e))?;
+        webhooks.push(Webhook {
+            config,
+            uri,
+            client: client.clone(),
+            failures: failures.clone(),
+        });
+    }
+    Ok(tokio::spawn(dispatch(
+        webhooks,
+        failures,
+        events.observe(),
+        cancel,
+    )))
+}
+
+async fn dispatch(
+    webhooks: Vec<Webhook>,
+    failures: Option<Arc<FailureStore>>,
+    mut observer: Observer,
+    cancel: CancellationToken,
+) {
+    let give_up = CancellationToken::new();
+    tokio::spawn({
+        let cancel = cancel.clone();
+        let give_up = give_up.clone();
+        async move {
+            cancel.cancelled().await;
+            tokio::time::sleep(DRAIN_TIMEOUT).await;
+            give_up.cancel();
+        }
+    });
+    let mut queues = Vec::new();
+    let mut workers = Vec::new();
+    for webhook in webhooks {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        queues.push((webhook.config.clone(), tx));
+        workers.push(tokio::spawn(webhook.run(
+            rx,
+            cancel.clone(),
+            give_up.clone(),
+        )));
+    }
+
+    loop {
+        let (device, message) = tokio::select! {
+            // drain the events before noticing the shutdown, so server-shutdown goes out
+            biased;
+            message = observer.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+            _ = cancel.cancelled() => break,
+        };
+        let delivery = Delivery {
+            id: hex::encode(&rand::thread_rng().gen::<[u8; 16]>()),
+            at_ms: unixtime::now_millis(),
+            device,
+            r#type: message.r#type,
+            data: message.data,
+        };
+        for (config, queue) in &queues {
+            let wanted = config
+                .types
+                .as_ref()
+                .is_none_or(|types| types.contains(&delivery.r#type));
+            if !wanted {
+                continue;
+            }
+            if let Err(mpsc::error::TrySendError::Full(delivery)) = queue.try_send(delivery.clone())
+            {
+                tracing::warn!("Webhook {} is not keeping up", config.url);
+                if let Some(failures) = &failures {
+                    failures
+                        .add(FailedDelivery {
+                            url: config.url.clone(),
+                            delivery,
+                            attempts: 0,
+                            error: "queue full".to_string(),
+                            failed_at_ms: unixtime::now_millis(),
+                        })
+                        .await;
+                }
+            }
+        }
+    }
+    drop(queues);
+    for worker in workers {
+        let _ = worker.await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eventbus::{Message, OverflowPolicy};
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Response, Server};
+    use std::convert::Infallible;
+
+    // the event type of every request received
+    fn serve() -> (String, mpsc::UnboundedReceiver<String>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let make = make_service_fn(move |_| {
+            let tx = tx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    let r#type = request.headers()["X-Kasse-Event"].to_str().unwrap();
+                    let _ = tx.send(r#type.to_string());
+                    async { Ok::<_, Infallible>(Response::new(Body::empty())) }
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make);
+        let url = format!("http://{}/hook", server.local_addr());
+        tokio::spawn(server);
+        (url, rx)
+    }
+
+    #[test]
+    fn signature_is_hmac_sha256() {
+        // RFC 4231, test case 2
+        assert_eq!(
+            signature("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn delivers_queue_on_shutdown() {
+        let (url, mut received) = serve();
+        let events = EventBus::new(16, OverflowPolicy::DropOldest);
+        let cancel = CancellationToken::new();
+        let config = WebhookConfig {
+            url,
+            types: None,
+            secret: None,
+            max_attempts: 1,
+        };
+        let webhooks = start(vec![config], None, &events, cancel.clone()).unwrap();
+
+        for r#type in ["barcode", "server-shutdown"] {
+            events.publish(Message {
+                r#type: r#type.to_string(),
+                data: "".into(),
+            });
+        }
+        // like the server shutting down
+        events.close();
+        cancel.cancel();
+        tokio::time::timeout(Duration::from_secs(5), webhooks)
+            .await
+            .unwrap()
+            .unwrap();
+
+        // nothing else was received if the worker gave up on them
+        let mut types = Vec::new();
+        while let Ok(r#type) = received.try_recv() {
+            types.push(r#type);
+        }
+        assert_eq!(types, ["barcode", "server-shutdown"]);
+    }
+}